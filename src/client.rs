@@ -1,14 +1,32 @@
+use crate::model::error::{BuildError, ErrorContext, RateLimit};
 use crate::model::request::DownloadRequest;
-use crate::model::response::DownloadResponse;
+use crate::model::response::{DownloadResponse, OutputMetadata};
 use crate::model::{error::CobaltError, response::InfoResponse};
-use log::info;
+use crate::options::DownloadOptions;
+use crate::picker::{
+    DEFAULT_CONCURRENCY, PickerDownload, PickerDownloads, SavedPicker, SavedPickerItem,
+};
+use crate::processing::ffmpeg::Ffmpeg;
+use crate::processing::{ProcessingError, ProcessingJob};
+use crate::retry::RetryPolicy;
+use crate::util::filetype;
+use crate::util::progress::ProgressSender;
+use crate::util::service;
+use crate::util::stream::{self, ResponseInfo, StreamError};
+use crate::util::tags;
+use crate::util::write::{self, Collision, SafePath, SaveName, TempPath};
+use futures::{StreamExt, TryStreamExt};
+use log::{info, warn};
 use reqwest::header::USER_AGENT;
 use reqwest::{
-    Client as HttpClient, Url,
+    Client as HttpClient, StatusCode, Url,
     header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
 };
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug, Clone)]
 pub struct Client {
@@ -18,6 +36,10 @@ pub struct Client {
     http: Arc<HttpClient>,
     user_agent: String,
     no_api_key: bool,
+    retry: RetryPolicy,
+    ffmpeg: Ffmpeg,
+    max_bytes: Option<u64>,
+    services: Arc<RwLock<Option<Vec<String>>>>,
 }
 
 #[derive(Debug, Default)]
@@ -28,6 +50,9 @@ pub struct ClientBuilder {
     http: Option<Arc<HttpClient>>,
     user_agent: Option<String>,
     no_api_key: bool,
+    retry: Option<RetryPolicy>,
+    ffmpeg_path: Option<PathBuf>,
+    max_bytes: Option<u64>,
 }
 
 impl ClientBuilder {
@@ -80,38 +105,74 @@ impl ClientBuilder {
         self
     }
 
+    /// Sets the policy used to retry transient failures when resolving and downloading.
+    ///
+    /// If not set, failed requests are not retried.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Sets the ffmpeg binary used for `local-processing` responses.
+    ///
+    /// If not set, `ffmpeg` is looked up in `PATH`.
+    pub fn ffmpeg_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ffmpeg_path = Some(path.into());
+        self
+    }
+
+    /// Sets the largest file that is downloaded, in bytes.
+    ///
+    /// Larger files fail with `error.api.content.too_large` before their body is read when
+    /// the server announces the size, or as soon as the limit is passed otherwise.
+    /// Can be overridden per download with [`DownloadOptions::max_bytes`].
+    ///
+    /// If not set, downloads are not limited.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
     /// Builds the `Client` instance.
-    pub fn build(self) -> Result<Client, url::ParseError> {
-        let base_url = self.base_url.expect("base_url is required");
+    ///
+    /// Returns a [`BuildError`] if the base URL is missing or invalid, or if the
+    /// credentials are missing or conflicting.
+    pub fn build(self) -> Result<Client, BuildError> {
+        let base_url = self.base_url.ok_or(BuildError::MissingBaseUrl)?;
 
         if !self.no_api_key && self.api_key.is_none() && self.bearer_token.is_none() {
-            panic!("Must set either api_key or bearer_token");
+            return Err(BuildError::MissingCredentials);
         }
 
         if !self.no_api_key && self.api_key.is_some() && self.bearer_token.is_some() {
-            panic!("Cannot set both api_key and bearer_token");
+            return Err(BuildError::ConflictingCredentials);
         }
 
         let user_agent = self
             .user_agent
             .unwrap_or_else(|| "ccobalt/0.0.1 (+client)".to_string());
 
-        let http_client = self.http.unwrap_or_else(|| {
-            Arc::new(
+        let http_client = match self.http {
+            Some(client) => client,
+            None => Arc::new(
                 HttpClient::builder()
                     .user_agent(user_agent.clone())
                     .build()
-                    .unwrap(),
-            )
-        });
+                    .map_err(BuildError::HttpClient)?,
+            ),
+        };
 
         Ok(Client {
-            base_url: base_url.parse()?,
+            base_url: base_url.parse().map_err(BuildError::InvalidBaseUrl)?,
             api_key: self.api_key,
             bearer_token: self.bearer_token,
             user_agent,
             http: http_client,
             no_api_key: self.no_api_key,
+            retry: self.retry.unwrap_or_else(RetryPolicy::none),
+            ffmpeg: self.ffmpeg_path.map(Ffmpeg::new).unwrap_or_default(),
+            max_bytes: self.max_bytes,
+            services: Arc::default(),
         })
     }
 }
@@ -122,36 +183,105 @@ impl Client {
         ClientBuilder::new()
     }
 
+    /// Returns the base URL of the API this client talks to.
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Returns the services supported by the instance, as cached by the last [`Client::get_info`] call.
+    pub fn services(&self) -> Option<Vec<String>> {
+        self.services
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns whether the instance supports the service of the given media URL.
+    ///
+    /// Returns `None` if the service is unknown or the services have not been cached yet.
+    pub fn supports_url(&self, url: &str) -> Option<bool> {
+        let service = service::detect_service(url)?;
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+
+        services
+            .as_ref()
+            .map(|services| services.iter().any(|s| s.eq_ignore_ascii_case(service)))
+    }
+
     /// Retrieves information about the API, such as version and supported features.
+    ///
+    /// The list of supported services is cached, so that requests for unsupported
+    /// services are refused without a round trip to the API.
     pub async fn get_info(&self) -> Result<InfoResponse, CobaltError> {
         let mut req = self.http.get(self.base_url.clone());
 
         req = req.header(ACCEPT, "application/json");
         req = req.header(USER_AGENT, &self.user_agent);
 
-        let res = req.send().await.map_err(|_| CobaltError {
-            code: "error.api.unreachable".into(),
-            context: None,
-        })?;
+        let res = req
+            .send()
+            .await
+            .map_err(|err| CobaltError::new("error.api.unreachable").with_source(err))?;
+
+        let status = res.status();
 
-        let body = res.text().await.map_err(|_| CobaltError {
-            code: "error.api.timed_out".into(),
-            context: None,
+        let body = res.text().await.map_err(|err| {
+            CobaltError::new("error.api.timed_out")
+                .with_status(status)
+                .with_source(err)
         })?;
 
         match serde_json::from_str::<InfoResponse>(&body) {
-            Ok(parsed) => Ok(parsed),
-            Err(_) => Err(CobaltError {
-                code: "error.api.unknown_response".into(),
-                context: None,
-            }),
+            Ok(parsed) => {
+                *self
+                    .services
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(parsed.cobalt.services.clone());
+                Ok(parsed)
+            }
+            Err(err) => Err(CobaltError::new("error.api.unknown_response")
+                .with_status(status)
+                .with_body(&body)
+                .with_source(err)),
         }
     }
 
     /// Resolves a download request and returns the download response.
+    ///
+    /// Transient failures are retried according to the client's [`RetryPolicy`].
+    /// If the supported services have been cached by [`Client::get_info`], requests for
+    /// other services fail with `error.api.service.unsupported` without contacting the API.
     pub async fn resolve_download(
         &self,
         request: &DownloadRequest,
+    ) -> Result<DownloadResponse, CobaltError> {
+        if self.supports_url(&request.url) == Some(false) {
+            return Err(
+                CobaltError::new("error.api.service.unsupported").with_context(ErrorContext {
+                    service: service::detect_service(&request.url).map(str::to_string),
+                    ..ErrorContext::default()
+                }),
+            );
+        }
+
+        self.retry
+            .run(
+                async || self.resolve_once(request).await,
+                |result| match result {
+                    Ok(DownloadResponse::Error { error }) | Err(error)
+                        if RetryPolicy::is_retryable(error) =>
+                    {
+                        Some(RetryPolicy::retry_after(error).unwrap_or_default())
+                    }
+                    _ => None,
+                },
+            )
+            .await
+    }
+
+    async fn resolve_once(
+        &self,
+        request: &DownloadRequest,
     ) -> Result<DownloadResponse, CobaltError> {
         let mut req = self.http.post(self.base_url.clone()).json(request);
 
@@ -168,25 +298,57 @@ impl Client {
             req = req.header(AUTHORIZATION, format!("Bearer {token}"));
         }
 
-        let res = req.send().await.map_err(|_| CobaltError {
-            code: "error.api.unreachable".into(),
-            context: None,
+        let res = req.send().await.map_err(|err| {
+            CobaltError::new(if err.is_timeout() {
+                "error.api.timed_out"
+            } else {
+                "error.api.unreachable"
+            })
+            .with_source(err)
         })?;
 
-        let body = res.text().await.map_err(|_| CobaltError {
-            code: "error.api.timed_out".into(),
-            context: None,
+        let status = res.status();
+        let rate_limit = RateLimit::from_headers(res.headers());
+
+        let body = res.text().await.map_err(|err| {
+            CobaltError::new("error.api.timed_out")
+                .with_status(status)
+                .with_source(err)
         })?;
 
         match serde_json::from_str::<DownloadResponse>(&body) {
+            Ok(DownloadResponse::Error { mut error }) => {
+                info!("ccobalt: {:#?}", error);
+                error = error.with_status(status);
+                if let Some(rate_limit) = rate_limit {
+                    error = error.with_rate_limit(rate_limit);
+                }
+                Ok(DownloadResponse::Error { error })
+            }
             Ok(parsed) => {
                 info!("ccobalt: {:#?}", parsed);
                 Ok(parsed)
             }
-            Err(_) => Err(CobaltError {
-                code: "error.api.unknown_response".into(),
-                context: None,
-            }),
+            // rate limited by a proxy or an instance that sent no JSON body
+            Err(err) if status == StatusCode::TOO_MANY_REQUESTS => {
+                let error = CobaltError::new("error.api.rate_exceeded")
+                    .with_status(status)
+                    .with_body(&body)
+                    .with_source(err);
+                Err(match rate_limit {
+                    Some(rate_limit) => error.with_rate_limit(rate_limit),
+                    None => error,
+                })
+            }
+            // a gateway in front of the instance failed, the API itself could not be reached
+            Err(err) if status.is_server_error() => Err(CobaltError::new("error.api.unreachable")
+                .with_status(status)
+                .with_body(&body)
+                .with_source(err)),
+            Err(err) => Err(CobaltError::new("error.api.unknown_response")
+                .with_status(status)
+                .with_body(&body)
+                .with_source(err)),
         }
     }
 
@@ -194,63 +356,766 @@ impl Client {
     ///
     /// Returns `Ok(None)` if the `Content-Length` header is not present or if no direct download URL is available.
     pub async fn get_size(&self, request: &DownloadRequest) -> Result<Option<u64>, CobaltError> {
-        let response = self.resolve_download(request).await?;
+        self.get_size_with(request, &DownloadOptions::default())
+            .await
+    }
 
-        if let Some(url) = response.get_download_url() {
-            let head_resp = self.http.head(&url).send().await.map_err(|_| CobaltError {
-                code: "error.api.head_request_failed".into(),
-                context: None,
-            })?;
-
-            if !head_resp.status().is_success() {
-                return Err(CobaltError {
-                    code: "error.api.head_request_failed".into(),
-                    context: None,
-                });
-            }
+    /// Same as [`Client::get_size`], but publishes the known total size to `options.progress`.
+    ///
+    /// Fails with `error.api.content.too_large` if the size is over the download size limit,
+    /// see [`ClientBuilder::max_bytes`].
+    pub async fn get_size_with(
+        &self,
+        request: &DownloadRequest,
+        options: &DownloadOptions,
+    ) -> Result<Option<u64>, CobaltError> {
+        cancellable(options, async {
+            let response = self.resolve_download(request).await?;
 
-            Ok(head_resp.content_length())
-        } else {
-            Ok(None)
-        }
+            if let Some(url) = response.get_download_url() {
+                let head_resp = self.http.head(&url).send().await.map_err(|err| {
+                    CobaltError::new("error.api.head_request_failed").with_source(err)
+                })?;
+
+                if !head_resp.status().is_success() {
+                    return Err(CobaltError::new("error.api.head_request_failed")
+                        .with_status(head_resp.status()));
+                }
+
+                let size = head_resp.content_length();
+                if let Some(size) = size
+                    && let Some(max_bytes) = self.max_bytes(options)
+                    && size > max_bytes
+                {
+                    return Err(stream_error(StreamError::TooLarge { size, max_bytes }));
+                }
+
+                if let Some(progress) = &options.progress {
+                    progress.send_modify(|progress| progress.total = size);
+                }
+
+                Ok(size)
+            } else {
+                Ok(None)
+            }
+        })
+        .await
     }
 
     /// Retrieves download information and downloads the file from the stream URL if available.
     pub async fn download(&self, request: &DownloadRequest) -> Result<Vec<u8>, CobaltError> {
-        let response = self.resolve_download(request).await?;
+        self.download_with(request, &DownloadOptions::default())
+            .await
+    }
 
-        if let Some(url) = response.get_download_url() {
-            let url = Url::from_str(&url).map_err(|_| CobaltError {
-                code: "error.api.invalid_url".into(),
-                context: None,
-            })?;
+    /// Same as [`Client::download`], with per-download options.
+    pub async fn download_with(
+        &self,
+        request: &DownloadRequest,
+        options: &DownloadOptions,
+    ) -> Result<Vec<u8>, CobaltError> {
+        let mut data = Vec::new();
+        self.download_to_writer_with(request, &mut data, options)
+            .await?;
+        Ok(data)
+    }
 
-            let stream = crate::util::stream::read_stream(Arc::clone(&self.http), url)
-                .await
-                .map_err(|_| CobaltError {
-                    code: "error.api.download_failed".into(),
-                    context: None,
-                })?;
-            Ok(stream)
-        } else {
-            Err(CobaltError {
-                code: "error.api.no_download_url".into(),
-                context: None,
-            })
-        }
+    /// Retrieves download information and writes the file into `writer` as it is downloaded.
+    ///
+    /// Returns the response metadata, with the file type detected from the first bytes
+    /// of the file.
+    pub async fn download_to_writer<W>(
+        &self,
+        request: &DownloadRequest,
+        writer: &mut W,
+    ) -> Result<ResponseInfo, CobaltError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.download_to_writer_with(request, writer, &DownloadOptions::default())
+            .await
+    }
+
+    /// Same as [`Client::download_to_writer`], with per-download options.
+    pub async fn download_to_writer_with<W>(
+        &self,
+        request: &DownloadRequest,
+        writer: &mut W,
+        options: &DownloadOptions,
+    ) -> Result<ResponseInfo, CobaltError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        cancellable(options, async {
+            let (url, _) = self.resolve_url(request).await?;
+
+            self.fetch_to_writer(
+                url,
+                writer,
+                options.progress.as_ref(),
+                self.max_bytes(options),
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Retrieves download information and streams the file straight to the specified directory.
+    ///
+    /// The extension is taken from the filename sent by cobalt, or detected from the first
+    /// bytes of the file, or taken from the response headers for unknown formats, see
+    /// [`ResponseInfo::extension`].
+    pub async fn download_to_path(
+        &self,
+        request: &DownloadRequest,
+        base_name: &str,
+        directory: impl AsRef<Path>,
+    ) -> Result<PathBuf, CobaltError> {
+        self.download_to_path_with(request, base_name, directory, &DownloadOptions::default())
+            .await
+    }
+
+    /// Same as [`Client::download_to_path`], with per-download options.
+    pub async fn download_to_path_with(
+        &self,
+        request: &DownloadRequest,
+        base_name: &str,
+        directory: impl AsRef<Path>,
+        options: &DownloadOptions,
+    ) -> Result<PathBuf, CobaltError> {
+        cancellable(options, async {
+            let (url, filename) = self.resolve_url(request).await?;
+
+            self.fetch_to_path(
+                url,
+                SaveName::Base(base_name, filename.as_deref()),
+                directory.as_ref(),
+                options.collision,
+                options.progress.as_ref(),
+                self.max_bytes(options),
+            )
+            .await
+        })
+        .await
     }
 
     /// Download and save the file to the specified directory.
+    ///
+    /// The file is streamed to disk, see [`Client::download_to_path`]. To keep the filename
+    /// suggested by cobalt instead of passing a base name, use [`Client::download_processed`].
     pub async fn download_and_save(
         &self,
         request: &DownloadRequest,
         base_name: &str,
-        directory: &str,
-    ) -> Result<std::path::PathBuf, CobaltError> {
-        let bytes = self.download(request).await?;
-        crate::util::write::save_to_file(&bytes, base_name, directory).map_err(|_| CobaltError {
-            code: "error.api.save_failed".into(),
-            context: None,
+        directory: impl AsRef<Path>,
+    ) -> Result<PathBuf, CobaltError> {
+        self.download_to_path(request, base_name, directory).await
+    }
+
+    /// Same as [`Client::download_and_save`], with per-download options.
+    pub async fn download_and_save_with(
+        &self,
+        request: &DownloadRequest,
+        base_name: &str,
+        directory: impl AsRef<Path>,
+        options: &DownloadOptions,
+    ) -> Result<PathBuf, CobaltError> {
+        self.download_to_path_with(request, base_name, directory, options)
+            .await
+    }
+
+    /// Retrieves download information and saves the final file into `directory`,
+    /// the way the cobalt web UI would.
+    ///
+    /// For `local-processing` responses every tunnel is downloaded and the result is
+    /// produced with ffmpeg, see [`ClientBuilder::ffmpeg_path`]. Tunnels and redirects are
+    /// saved as is. The file is named after the filename sent by cobalt, which follows the
+    /// request's `filename_style`, or the `Content-Disposition` header of the tunnel if cobalt
+    /// sent none; see [`write::sanitize_filename`]. The metadata sent with the job is written
+    /// into it unless `disable_metadata` is set.
+    pub async fn download_processed(
+        &self,
+        request: &DownloadRequest,
+        directory: impl AsRef<Path>,
+    ) -> Result<PathBuf, CobaltError> {
+        self.download_processed_with(request, directory, &DownloadOptions::default())
+            .await
+    }
+
+    /// Same as [`Client::download_processed`], with per-download options.
+    ///
+    /// Progress is reported for each tunnel in turn.
+    pub async fn download_processed_with(
+        &self,
+        request: &DownloadRequest,
+        directory: impl AsRef<Path>,
+        options: &DownloadOptions,
+    ) -> Result<PathBuf, CobaltError> {
+        cancellable(options, async {
+            let response = self.resolve_download(request).await?;
+
+            let mut job = match ProcessingJob::from_response(response) {
+                Ok(job) => job,
+                Err(DownloadResponse::Error { error }) => return Err(error),
+                Err(DownloadResponse::Tunnel { url, filename })
+                | Err(DownloadResponse::Redirect { url, filename }) => {
+                    let url = Url::from_str(&url).map_err(|err| {
+                        CobaltError::new("error.api.invalid_url").with_source(err)
+                    })?;
+
+                    return self
+                        .fetch_to_path(
+                            url,
+                            SaveName::Suggested(Some(&filename)),
+                            directory.as_ref(),
+                            options.collision,
+                            options.progress.as_ref(),
+                            self.max_bytes(options),
+                        )
+                        .await;
+                }
+                Err(_) => return Err(CobaltError::new("error.api.not_local_processing")),
+            };
+
+            let metadata = job
+                .output
+                .metadata
+                .take()
+                .filter(|_| !request.disable_metadata.unwrap_or(false));
+
+            self.process(&job, metadata, directory.as_ref(), options)
+                .await
+        })
+        .await
+    }
+
+    /// Downloads the inputs of a processing job and runs it, removing the inputs afterwards.
+    ///
+    /// The metadata is written into the temporary output, so only the tagged file is
+    /// published under its final name.
+    async fn process(
+        &self,
+        job: &ProcessingJob,
+        metadata: Option<OutputMetadata>,
+        directory: &Path,
+        options: &DownloadOptions,
+    ) -> Result<PathBuf, CobaltError> {
+        let output = match write::safe_path(directory, &job.output.filename, options.collision) {
+            Ok(SafePath::Write(path)) => path,
+            // skipped, the existing file is not downloaded nor tagged again
+            Ok(SafePath::Exists(path)) => return Ok(path),
+            Err(err) => return Err(CobaltError::new("error.api.save_failed").with_source(err)),
+        };
+        // inputs and the unfinished output are removed when they go out of scope
+        let mut inputs = Vec::with_capacity(job.input_count());
+        let temp = TempPath::new(&output);
+
+        for (i, tunnel) in job.tunnel.iter().take(job.input_count()).enumerate() {
+            let url = Url::from_str(tunnel)
+                .map_err(|err| CobaltError::new("error.api.invalid_url").with_source(err))?;
+            let input = input_path(&output, i);
+
+            let mut file = tokio::fs::File::create(input.path())
+                .await
+                .map_err(|err| CobaltError::new("error.api.save_failed").with_source(err))?;
+            inputs.push(input);
+
+            self.fetch_to_writer(
+                url,
+                &mut file,
+                options.progress.as_ref(),
+                self.max_bytes(options),
+            )
+            .await?;
+            file.flush()
+                .await
+                .map_err(|err| CobaltError::new("error.api.save_failed").with_source(err))?;
+        }
+
+        let input_paths: Vec<PathBuf> = inputs
+            .iter()
+            .map(|input| input.path().to_path_buf())
+            .collect();
+        self.run_job(job, &input_paths, temp.path())
+            .await
+            .map_err(processing_error)?;
+
+        // merging can produce a file larger than each of its inputs
+        if let Some(max_bytes) = self.max_bytes(options) {
+            let size = tokio::fs::metadata(temp.path())
+                .await
+                .map_err(|err| CobaltError::new("error.api.save_failed").with_source(err))?
+                .len();
+            if size > max_bytes {
+                return Err(stream_error(StreamError::TooLarge { size, max_bytes }));
+            }
+        }
+
+        if let Some(metadata) = metadata {
+            let path = temp.path().to_path_buf();
+            let result =
+                tokio::task::spawn_blocking(move || tags::write_tags(&path, &metadata)).await;
+
+            // the file is still usable without tags
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => warn!("Failed to write tags to {}: {err}", output.display()),
+                Err(err) => warn!("Failed to write tags to {}: {err}", output.display()),
+            }
+        }
+
+        temp.persist(&output)
+            .await
+            .map_err(|err| CobaltError::new("error.api.save_failed").with_source(err))?;
+
+        Ok(output)
+    }
+
+    /// Runs a processing job with the built-in MP4 muxer when the `mp4` feature is enabled
+    /// and the job allows it, falling back to ffmpeg otherwise, or when the muxer cannot
+    /// handle the inputs. The muxer error is only returned if ffmpeg cannot be spawned.
+    async fn run_job(
+        &self,
+        job: &ProcessingJob,
+        inputs: &[PathBuf],
+        output: &Path,
+    ) -> Result<(), ProcessingError> {
+        #[cfg(feature = "mp4")]
+        if crate::processing::mp4::supports(&job.kind) {
+            let muxer_error = match crate::processing::mp4::run(job, inputs, output).await {
+                Err(err @ (ProcessingError::Unsupported(_) | ProcessingError::InvalidInput(_))) => {
+                    err
+                }
+                result => return result,
+            };
+
+            // without ffmpeg, say why the muxer could not handle the job
+            return match self.ffmpeg.run(job, inputs, output).await {
+                Err(ProcessingError::Spawn(_)) => Err(muxer_error),
+                result => result,
+            };
+        }
+
+        self.ffmpeg.run(job, inputs, output).await
+    }
+
+    /// Retrieves a picker response and downloads every item, plus the audio track if any.
+    ///
+    /// Used for Instagram carousels, TikTok slideshows and other posts with several media.
+    /// Items are downloaded concurrently and returned in order.
+    pub async fn download_picker(
+        &self,
+        request: &DownloadRequest,
+    ) -> Result<PickerDownloads, CobaltError> {
+        self.download_picker_with(request, &DownloadOptions::default())
+            .await
+    }
+
+    /// Same as [`Client::download_picker`], with per-download options.
+    ///
+    /// Progress is not reported for picker items.
+    pub async fn download_picker_with(
+        &self,
+        request: &DownloadRequest,
+        options: &DownloadOptions,
+    ) -> Result<PickerDownloads, CobaltError> {
+        cancellable(options, async {
+            let (items, audio) = self.resolve_picker(request).await?;
+            let concurrency = options.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1);
+
+            let fetch = async |kind: String, url: Url| {
+                let mut bytes = Vec::new();
+                let info = self
+                    .fetch_to_writer(url, &mut bytes, None, self.max_bytes(options))
+                    .await?;
+
+                Ok::<_, CobaltError>(PickerDownload {
+                    kind,
+                    bytes,
+                    file_type: info.file_type,
+                })
+            };
+
+            let items = futures::stream::iter(items)
+                .map(|(kind, url)| fetch(kind, url))
+                .buffered(concurrency)
+                .try_collect()
+                .await?;
+
+            let audio = match audio {
+                Some(url) => Some(fetch("audio".to_string(), url).await?),
+                None => None,
+            };
+
+            Ok(PickerDownloads { items, audio })
+        })
+        .await
+    }
+
+    /// Retrieves a picker response and saves every item, plus the audio track if any,
+    /// to the specified directory.
+    ///
+    /// Items are saved as `base_name_1.jpg`, `base_name_2.mp4` and so on, and the
+    /// audio track as `base_name_audio.mp3`, with extensions detected from the files.
+    pub async fn save_picker(
+        &self,
+        request: &DownloadRequest,
+        base_name: &str,
+        directory: impl AsRef<Path>,
+    ) -> Result<SavedPicker, CobaltError> {
+        self.save_picker_with(request, base_name, directory, &DownloadOptions::default())
+            .await
+    }
+
+    /// Same as [`Client::save_picker`], with per-download options.
+    ///
+    /// Progress is not reported for picker items.
+    pub async fn save_picker_with(
+        &self,
+        request: &DownloadRequest,
+        base_name: &str,
+        directory: impl AsRef<Path>,
+        options: &DownloadOptions,
+    ) -> Result<SavedPicker, CobaltError> {
+        cancellable(options, async {
+            let (items, audio) = self.resolve_picker(request).await?;
+            let concurrency = options.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1);
+            let directory = directory.as_ref();
+
+            let save = async |kind: String, url: Url, name: String| {
+                let path = self
+                    .fetch_to_path(
+                        url,
+                        SaveName::Base(&name, None),
+                        directory,
+                        options.collision,
+                        None,
+                        self.max_bytes(options),
+                    )
+                    .await?;
+                Ok::<_, CobaltError>(SavedPickerItem { kind, path })
+            };
+
+            let items = futures::stream::iter(items.into_iter().enumerate())
+                .map(|(index, (kind, url))| save(kind, url, format!("{base_name}_{}", index + 1)))
+                .buffered(concurrency)
+                .try_collect()
+                .await?;
+
+            let audio = match audio {
+                Some(url) => {
+                    Some(save("audio".to_string(), url, format!("{base_name}_audio")).await?)
+                }
+                None => None,
+            };
+
+            Ok(SavedPicker { items, audio })
         })
+        .await
+    }
+
+    /// Resolves a download request that is expected to return a picker response.
+    ///
+    /// Returns the kind and URL of every item, and the URL of the audio track.
+    // the closures return `CobaltError`, which keeps its fields inline
+    #[allow(clippy::result_large_err)]
+    async fn resolve_picker(
+        &self,
+        request: &DownloadRequest,
+    ) -> Result<(Vec<(String, Url)>, Option<Url>), CobaltError> {
+        let parse = |url: &str| {
+            Url::from_str(url)
+                .map_err(|err| CobaltError::new("error.api.invalid_url").with_source(err))
+        };
+
+        match self.resolve_download(request).await? {
+            DownloadResponse::Picker { picker, audio, .. } => {
+                let items = picker
+                    .into_iter()
+                    .map(|item| Ok((item.kind, parse(&item.url)?)))
+                    .collect::<Result<_, CobaltError>>()?;
+                let audio = audio.as_deref().map(parse).transpose()?;
+
+                Ok((items, audio))
+            }
+            DownloadResponse::Error { error } => Err(error),
+            _ => Err(CobaltError::new("error.api.not_picker")),
+        }
+    }
+
+    /// Returns the download size limit, from the options or the client.
+    fn max_bytes(&self, options: &DownloadOptions) -> Option<u64> {
+        options.max_bytes.or(self.max_bytes)
+    }
+
+    /// Downloads the file at `url` into `writer`, returning the response metadata.
+    async fn fetch_to_writer<W>(
+        &self,
+        url: Url,
+        writer: &mut W,
+        progress: Option<&ProgressSender>,
+        max_bytes: Option<u64>,
+    ) -> Result<ResponseInfo, CobaltError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        // the writer cannot be rewound, so only retry until the body starts arriving
+        let response = self
+            .retry
+            .run(
+                async || {
+                    let response =
+                        stream::open_stream(Arc::clone(&self.http), url.clone(), 0).await?;
+                    Ok(response.error_for_status()?)
+                },
+                |result| {
+                    result
+                        .as_ref()
+                        .is_err_and(RetryPolicy::is_retryable_stream)
+                        .then_some(Duration::ZERO)
+                },
+            )
+            .await
+            .map_err(stream_error)?;
+
+        let mut info = ResponseInfo::from_response(&response);
+        let head = stream::copy_body(response, writer, 0, progress, max_bytes)
+            .await
+            .map_err(stream_error)?;
+        info.file_type = filetype::get_sig(&head);
+
+        Ok(info)
+    }
+
+    /// Downloads the file at `url` into `directory`, returning the path of the saved file.
+    async fn fetch_to_path(
+        &self,
+        url: Url,
+        name: SaveName<'_>,
+        directory: &Path,
+        collision: Collision,
+        progress: Option<&ProgressSender>,
+        max_bytes: Option<u64>,
+    ) -> Result<PathBuf, CobaltError> {
+        // a failed attempt leaves a `.part` file behind, which the next attempt resumes
+        self.retry
+            .run(
+                async || {
+                    write::save_stream_as(
+                        Arc::clone(&self.http),
+                        url.clone(),
+                        name,
+                        directory,
+                        collision,
+                        progress,
+                        max_bytes,
+                    )
+                    .await
+                },
+                |result| {
+                    result
+                        .as_ref()
+                        .is_err_and(RetryPolicy::is_retryable_stream)
+                        .then_some(Duration::ZERO)
+                },
+            )
+            .await
+            .map_err(stream_error)
+    }
+
+    /// Resolves a download request into the direct download URL, along with the filename
+    /// sent by cobalt for tunnels and redirects.
+    async fn resolve_url(
+        &self,
+        request: &DownloadRequest,
+    ) -> Result<(Url, Option<String>), CobaltError> {
+        let response = self.resolve_download(request).await?;
+
+        if let DownloadResponse::Error { error } = response {
+            return Err(error);
+        }
+
+        let Some(url) = response.get_download_url() else {
+            return Err(CobaltError::new("error.api.no_download_url"));
+        };
+        let url = Url::from_str(&url)
+            .map_err(|err| CobaltError::new("error.api.invalid_url").with_source(err))?;
+
+        let filename = match response {
+            DownloadResponse::Tunnel { filename, .. }
+            | DownloadResponse::Redirect { filename, .. } => Some(filename),
+            _ => None,
+        };
+
+        Ok((url, filename))
+    }
+}
+
+/// Returns a hidden temporary path for an input of a processing job next to its output.
+///
+/// The name does not include the output's, which may already use the whole length allowed
+/// for a file name. ffmpeg and the muxer detect the format from the contents.
+fn input_path(output: &Path, index: usize) -> TempPath {
+    TempPath::new(&output.with_file_name(format!("input{index}")))
+}
+
+/// Runs a download, stopping it with `error.api.cancelled` once the cancellation token in
+/// `options` is triggered.
+///
+/// Dropping the download removes its partial files, see [`write::save_stream_as`].
+async fn cancellable<T>(
+    options: &DownloadOptions,
+    download: impl Future<Output = Result<T, CobaltError>>,
+) -> Result<T, CobaltError> {
+    let Some(token) = &options.cancel else {
+        return download.await;
+    };
+
+    tokio::select! {
+        biased;
+        () = token.cancelled() => Err(CobaltError::new("error.api.cancelled")),
+        result = download => result,
+    }
+}
+
+/// Maps a failed download to `error.api.download_failed`, `error.api.save_failed` when
+/// writing the file failed, or `error.api.content.too_large` with the size in the context.
+fn stream_error(err: StreamError) -> CobaltError {
+    match err {
+        StreamError::Http(err) => {
+            let error = CobaltError::new("error.api.download_failed");
+            match err.status() {
+                Some(status) => error.with_status(status),
+                None => error,
+            }
+            .with_source(err)
+        }
+        StreamError::Io(err) => CobaltError::new("error.api.save_failed").with_source(err),
+        StreamError::TooLarge { size, .. } => CobaltError::new("error.api.content.too_large")
+            .with_context(ErrorContext {
+                size: Some(size),
+                ..ErrorContext::default()
+            })
+            .with_source(err),
+    }
+}
+
+/// Maps a failed processing job to `error.api.processing.unavailable` when ffmpeg could not
+/// be started, or `error.api.processing.failed`.
+fn processing_error(err: ProcessingError) -> CobaltError {
+    match err {
+        ProcessingError::Spawn(_) => {
+            CobaltError::new("error.api.processing.unavailable").with_source(err)
+        }
+        ProcessingError::Failed { ref stderr, .. } => {
+            CobaltError::new("error.api.processing.failed")
+                .with_body(stderr)
+                .with_source(err)
+        }
+        ProcessingError::Unsupported(_) => {
+            CobaltError::new("error.api.processing.unsupported").with_source(err)
+        }
+        ProcessingError::MissingInput
+        | ProcessingError::Io(_)
+        | ProcessingError::InvalidInput(_) => {
+            CobaltError::new("error.api.processing.failed").with_source(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::error::CobaltErrorKind;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn test_build_errors() {
+        assert!(matches!(
+            Client::builder().api_key("key").build(),
+            Err(BuildError::MissingBaseUrl)
+        ));
+        assert!(matches!(
+            Client::builder()
+                .base_url("https://api.example.com")
+                .build(),
+            Err(BuildError::MissingCredentials)
+        ));
+        assert!(matches!(
+            Client::builder()
+                .base_url("https://api.example.com")
+                .api_key("key")
+                .bearer_token("token")
+                .build(),
+            Err(BuildError::ConflictingCredentials)
+        ));
+        assert!(matches!(
+            Client::builder()
+                .base_url("not a url")
+                .no_api_key(true)
+                .build(),
+            Err(BuildError::InvalidBaseUrl(_))
+        ));
+    }
+
+    #[test]
+    fn test_build() {
+        let client = Client::builder()
+            .base_url("https://api.example.com")
+            .api_key("key")
+            .build()
+            .unwrap();
+
+        assert_eq!(client.base_url().as_str(), "https://api.example.com/");
+    }
+
+    #[test]
+    fn test_input_path_length() {
+        let directory = std::env::temp_dir().join(format!("ccobalt-{:x}", fastrand::u64(..)));
+        std::fs::create_dir_all(&directory).unwrap();
+        let name = write::sanitize_filename(&format!("{}.mp4", "a".repeat(300))).unwrap();
+        assert_eq!(name.len(), 240);
+        let output = directory.join(name);
+
+        let input = input_path(&output, 0);
+        std::fs::File::create(input.path()).unwrap();
+        assert!(
+            input
+                .path()
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with('.')
+        );
+        let temp = TempPath::new(&output);
+        std::fs::File::create(temp.path()).unwrap();
+        drop((input, temp));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        let token = CancellationToken::new();
+        let options = DownloadOptions {
+            cancel: Some(token.clone()),
+            ..DownloadOptions::default()
+        };
+
+        let result = cancellable(&options, async { Ok(1) }).await;
+        assert_eq!(result.unwrap(), 1);
+
+        // the partial file goes away with the dropped download
+        let path = std::env::temp_dir().join(format!("ccobalt-{:x}.part", fastrand::u64(..)));
+        std::fs::write(&path, b"partial").unwrap();
+        let download = async {
+            let _part = TempPath::at(path.clone());
+            token.cancel();
+            std::future::pending::<Result<(), CobaltError>>().await
+        };
+
+        let err = cancellable(&options, download).await.unwrap_err();
+        assert_eq!(err.kind(), CobaltErrorKind::Cancelled);
+        assert!(!path.exists());
     }
 }
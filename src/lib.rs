@@ -1,5 +1,14 @@
 pub mod client;
 pub mod model;
+pub mod options;
+pub mod picker;
+pub mod pool;
+pub mod processing;
+pub mod retry;
 pub mod util;
 
 pub use client::{Client, ClientBuilder};
+pub use options::DownloadOptions;
+pub use pool::InstancePool;
+pub use retry::RetryPolicy;
+pub use tokio_util::sync::CancellationToken;
@@ -1,59 +1,443 @@
+use super::messages::{self, English, MessageCatalog};
+use reqwest::header::{HeaderMap, HeaderName, RETRY_AFTER};
 use serde::Deserialize;
+use std::error::Error;
 use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Maximum number of bytes of a response body kept in a [`CobaltError`].
+const BODY_SNIPPET_LEN: usize = 512;
 
 #[derive(Debug, Deserialize)]
 pub struct CobaltError {
     pub code: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub context: Option<ErrorContext>,
+    /// Rate limit information sent by the instance along with the error.
+    #[serde(skip)]
+    pub rate_limit: Option<RateLimit>,
+    /// HTTP status code of the response that caused the error, if any.
+    #[serde(skip)]
+    pub status: Option<u16>,
+    /// Beginning of the response body that could not be understood, if any.
+    #[serde(skip)]
+    pub body: Option<String>,
+    #[serde(skip)]
+    source: Option<Box<dyn Error + Send + Sync>>,
 }
 
-#[derive(Debug, Deserialize)]
+impl CobaltError {
+    /// Creates a new error with the given code and no context.
+    pub fn new(code: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            context: None,
+            rate_limit: None,
+            status: None,
+            body: None,
+            source: None,
+        }
+    }
+
+    /// Returns the human-readable message for the given locale, e.g. `ru` or `uk-UA`.
+    ///
+    /// Uses the catalog registered with [`register_catalog`](super::messages::register_catalog)
+    /// and falls back to the built-in English messages, then to the error code.
+    pub fn message(&self, locale: &str) -> String {
+        messages::catalog(locale)
+            .and_then(|catalog| messages::render(catalog.as_ref(), self))
+            .unwrap_or_else(|| self.message_with(&English))
+    }
+
+    /// Returns the human-readable message from the given catalog, or the error code
+    /// if the catalog has no message for it.
+    pub fn message_with(&self, catalog: &dyn MessageCatalog) -> String {
+        messages::render(catalog, self).unwrap_or_else(|| self.code.clone())
+    }
+
+    /// Returns the typed error kind for the error code.
+    pub fn kind(&self) -> CobaltErrorKind {
+        CobaltErrorKind::from_code(&self.code)
+    }
+
+    /// Sets the HTTP status code of the response that caused the error.
+    pub(crate) fn with_status(mut self, status: reqwest::StatusCode) -> Self {
+        self.status = Some(status.as_u16());
+        self
+    }
+
+    /// Keeps the beginning of the response body, up to 512 bytes.
+    pub(crate) fn with_body(mut self, body: &str) -> Self {
+        let mut end = body.len().min(BODY_SNIPPET_LEN);
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.body = Some(body[..end].to_string());
+        self
+    }
+
+    /// Sets the context of the error.
+    pub(crate) fn with_context(mut self, context: ErrorContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Sets the underlying error, returned by [`Error::source`].
+    pub(crate) fn with_source(mut self, source: impl Error + Send + Sync + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Attaches the rate limit information and, for `error.api.rate_exceeded`, fills in
+    /// `context.limit` with the number of seconds to wait, unless the instance already sent it.
+    pub(crate) fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+
+        if self.is_rate_exceeded()
+            && let Some(wait) = self.requested_wait()
+        {
+            let context = self.context.get_or_insert_with(ErrorContext::default);
+            if context.limit.is_none() {
+                context.limit = Some(wait.as_secs().try_into().unwrap_or(u32::MAX));
+            }
+        }
+
+        self
+    }
+
+    /// Returns the wait requested by the instance before the next request.
+    ///
+    /// Instances send the `RateLimit-*` headers on every response, so the window reset
+    /// only applies once it is exhausted or the error is about the rate limit itself.
+    pub(crate) fn requested_wait(&self) -> Option<Duration> {
+        let rate_limit = self.rate_limit.as_ref()?;
+
+        if self.is_rate_exceeded() || rate_limit.remaining == Some(0) {
+            rate_limit.wait_time()
+        } else {
+            rate_limit.retry_after
+        }
+    }
+
+    fn is_rate_exceeded(&self) -> bool {
+        self.code.eq_ignore_ascii_case("error.api.rate_exceeded")
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct ErrorContext {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub service: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub limit: Option<u32>,
+    /// Size of the file in bytes, for `error.api.content.too_large`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+macro_rules! error_kinds {
+    ($($variant:ident => $code:literal,)*) => {
+        /// Typed version of the [`CobaltError`] code.
+        ///
+        /// Covers the codes sent by cobalt instances and the ones produced by this client.
+        /// Codes that are not known yet are kept in [`CobaltErrorKind::Unknown`].
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum CobaltErrorKind {
+            $($variant,)*
+            Unknown(String),
+        }
+
+        impl CobaltErrorKind {
+            /// Parses an error code, ignoring case.
+            pub fn from_code(code: &str) -> Self {
+                match code.to_ascii_lowercase().as_str() {
+                    $($code => CobaltErrorKind::$variant,)*
+                    _ => CobaltErrorKind::Unknown(code.to_string()),
+                }
+            }
+
+            /// Returns the error code for this kind.
+            pub fn as_code(&self) -> &str {
+                match self {
+                    $(CobaltErrorKind::$variant => $code,)*
+                    CobaltErrorKind::Unknown(code) => code,
+                }
+            }
+        }
+    };
+}
+
+error_kinds! {
+    Generic => "error.api.generic",
+    Unreachable => "error.api.unreachable",
+    TimedOut => "error.api.timed_out",
+    RateExceeded => "error.api.rate_exceeded",
+    Capacity => "error.api.capacity",
+    UnknownResponse => "error.api.unknown_response",
+    InvalidBody => "error.api.invalid_body",
+    AuthJwtMissing => "error.api.auth.jwt.missing",
+    AuthJwtInvalid => "error.api.auth.jwt.invalid",
+    AuthKeyMissing => "error.api.auth.key.missing",
+    AuthKeyNotApiKey => "error.api.auth.key.not_api_key",
+    AuthKeyInvalid => "error.api.auth.key.invalid",
+    AuthKeyNotFound => "error.api.auth.key.not_found",
+    AuthKeyInvalidIp => "error.api.auth.key.invalid_ip",
+    AuthKeyUaNotAllowed => "error.api.auth.key.ua_not_allowed",
+    AuthTurnstileMissing => "error.api.auth.turnstile.missing",
+    AuthTurnstileInvalid => "error.api.auth.turnstile.invalid",
+    ServiceUnsupported => "error.api.service.unsupported",
+    ServiceDisabled => "error.api.service.disabled",
+    ServiceAudioNotSupported => "error.api.service.audio_not_supported",
+    LinkInvalid => "error.api.link.invalid",
+    LinkUnsupported => "error.api.link.unsupported",
+    FetchFail => "error.api.fetch.fail",
+    FetchCritical => "error.api.fetch.critical",
+    FetchCriticalCore => "error.api.fetch.critical.core",
+    FetchEmpty => "error.api.fetch.empty",
+    FetchRate => "error.api.fetch.rate",
+    FetchShortLink => "error.api.fetch.short_link",
+    ContentTooLong => "error.api.content.too_long",
+    ContentTooLarge => "error.api.content.too_large",
+    VideoUnavailable => "error.api.content.video.unavailable",
+    VideoLive => "error.api.content.video.live",
+    VideoPrivate => "error.api.content.video.private",
+    VideoAge => "error.api.content.video.age",
+    VideoRegion => "error.api.content.video.region",
+    PostUnavailable => "error.api.content.post.unavailable",
+    PostPrivate => "error.api.content.post.private",
+    PostAge => "error.api.content.post.age",
+    YoutubeCodec => "error.api.youtube.codec",
+    YoutubeNoMatchingFormat => "error.api.youtube.no_matching_format",
+    YoutubeDecipher => "error.api.youtube.decipher",
+    YoutubeLogin => "error.api.youtube.login",
+    YoutubeTokenExpired => "error.api.youtube.token_expired",
+    YoutubeNoHlsStreams => "error.api.youtube.no_hls_streams",
+    YoutubeApiError => "error.api.youtube.api_error",
+    YoutubeTemporaryDisabled => "error.api.youtube.temporary_disabled",
+    YoutubeDrm => "error.api.youtube.drm",
+    YoutubeNoSessionTokens => "error.api.youtube.no_session_tokens",
+    DownloadFailed => "error.api.download_failed",
+    SaveFailed => "error.api.save_failed",
+    NoDownloadUrl => "error.api.no_download_url",
+    InvalidUrl => "error.api.invalid_url",
+    HeadRequestFailed => "error.api.head_request_failed",
+    NotPicker => "error.api.not_picker",
+    NotLocalProcessing => "error.api.not_local_processing",
+    ProcessingUnavailable => "error.api.processing.unavailable",
+    ProcessingFailed => "error.api.processing.failed",
+    ProcessingUnsupported => "error.api.processing.unsupported",
+    Cancelled => "error.api.cancelled",
+}
+
+impl CobaltErrorKind {
+    /// Returns whether the error is transient and the request may succeed if retried later.
+    pub fn is_retryable(&self) -> bool {
+        use CobaltErrorKind::*;
+
+        matches!(
+            self,
+            Unreachable | TimedOut | Capacity | RateExceeded | FetchRate
+        )
+    }
+
+    /// Returns whether the error is caused by the requested link or content,
+    /// so that retrying or switching instances will not help.
+    pub fn is_user_error(&self) -> bool {
+        use CobaltErrorKind::*;
+
+        matches!(
+            self,
+            InvalidBody
+                | ServiceUnsupported
+                | ServiceAudioNotSupported
+                | LinkInvalid
+                | LinkUnsupported
+                | FetchShortLink
+                | ContentTooLong
+                | ContentTooLarge
+                | VideoUnavailable
+                | VideoLive
+                | VideoPrivate
+                | VideoAge
+                | VideoRegion
+                | PostUnavailable
+                | PostPrivate
+                | PostAge
+                | InvalidUrl
+        )
+    }
+
+    /// Returns whether the error is caused by the cobalt instance or the upstream
+    /// service, such as a disabled service, a blocked downloader or a broken extractor.
+    pub fn is_service_error(&self) -> bool {
+        use CobaltErrorKind::*;
+
+        matches!(
+            self,
+            Generic
+                | UnknownResponse
+                | ServiceDisabled
+                | FetchFail
+                | FetchCritical
+                | FetchCriticalCore
+                | FetchEmpty
+                | FetchRate
+                | YoutubeCodec
+                | YoutubeNoMatchingFormat
+                | YoutubeDecipher
+                | YoutubeLogin
+                | YoutubeTokenExpired
+                | YoutubeNoHlsStreams
+                | YoutubeApiError
+                | YoutubeTemporaryDisabled
+                | YoutubeDrm
+                | YoutubeNoSessionTokens
+        )
+    }
+}
+
+impl fmt::Display for CobaltErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_code())
+    }
+}
+
+/// Rate limit headers sent by a cobalt instance, usually with a `429` response.
+///
+/// Both `Retry-After` and the `RateLimit-*` headers from the IETF draft are understood,
+/// including the combined `RateLimit: limit=.., remaining=.., reset=..` form.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimit {
+    /// Value of the `Retry-After` header.
+    pub retry_after: Option<Duration>,
+    /// Time until the rate limit window resets.
+    pub reset: Option<Duration>,
+    /// Requests left in the current window.
+    pub remaining: Option<u32>,
+    /// Requests allowed per window.
+    pub limit: Option<u32>,
+}
+
+const RATELIMIT: HeaderName = HeaderName::from_static("ratelimit");
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+impl RateLimit {
+    /// Parses the rate limit headers, returns `None` if none of them are present.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name: &HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+
+        let mut rate_limit = RateLimit {
+            retry_after: header(&RETRY_AFTER).and_then(parse_retry_after),
+            reset: header(&RATELIMIT_RESET)
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs),
+            remaining: header(&RATELIMIT_REMAINING).and_then(|value| value.trim().parse().ok()),
+            limit: header(&RATELIMIT_LIMIT).and_then(|value| value.trim().parse().ok()),
+        };
+
+        if let Some(combined) = header(&RATELIMIT) {
+            for item in combined.split([',', ';']) {
+                let Some((key, value)) = item.split_once('=') else {
+                    continue;
+                };
+                let Ok(value) = value.trim().parse::<u64>() else {
+                    continue;
+                };
+
+                match key.trim() {
+                    "limit" => rate_limit.limit = rate_limit.limit.or(value.try_into().ok()),
+                    "remaining" => {
+                        rate_limit.remaining = rate_limit.remaining.or(value.try_into().ok())
+                    }
+                    "reset" => {
+                        rate_limit.reset = rate_limit.reset.or(Some(Duration::from_secs(value)))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        (rate_limit != RateLimit::default()).then_some(rate_limit)
+    }
+
+    /// Returns how long to wait before the next request, preferring `Retry-After`.
+    pub fn wait_time(&self) -> Option<Duration> {
+        self.retry_after.or(self.reset)
+    }
+}
+
+/// Parses a `Retry-After` value, either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
 }
 
 impl fmt::Display for CobaltError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.code.to_ascii_lowercase().as_str() {
-            "error.api.unreachable" => f.write_str("API unreachable (try again later)"),
-            "error.api.timed_out" => f.write_str("API timeout (try again later)"),
-            "error.api.rate_exceeded" => f.write_str("Rate limited (try again later)"),
-            "error.api.capacity" => f.write_str("API busy (try again later)"),
-            "error.api.generic" => f.write_str("General API error (try again later)"),
-            "error.api.unknown_response" => f.write_str("Download failure. Make sure the link is valid. (unknown response)"),
-            "error.api.service.unsupported" => f.write_str("That service or website is not supported."),
-            "error.api.service.disabled" => f.write_str("Downloading from that service or website is temporarily disabled."),
-            "error.api.link.invalid" => f.write_str("That link is invalid. Make sure it is correct."),
-            "error.api.link.unsupported" => f.write_str("That link or format is unsupported."),
-            "error.api.fetch.fail" => f.write_str("Failed to fetch the media. Make sure the link is valid, or try again later."),
-            "error.api.fetch.critical" => f.write_str("Critical error fetching the media. Make sure the link is valid, or try again later."),
-            "error.api.fetch.empty" => f.write_str("The service or website returned no data. This may be caused by the site blocking the downloader (try again later)"),
-            "error.api.fetch.rate" => f.write_str("The service or website has rate limited the downloader (try again later)"),
-            "error.api.fetch.short_link" => f.write_str("Unable to resolve the shortlink. Try using the full link to the media."),
-            "error.api.content.too_long" => f.write_str("The requested content is too big."),
-            "error.api.content.video.unavailable" => f.write_str("That video is unavailable. Make sure it is not region or age restricted, and is not private."),
-            "error.api.content.video.live" => f.write_str("Live videos are unsupported."),
-            "error.api.content.video.private" => f.write_str("That video is private."),
-            "error.api.content.video.age" => f.write_str("That video is age restricted."),
-            "error.api.content.video.region" => f.write_str("That video is region restricted."),
-            "error.api.content.post.unavailable" => f.write_str("That post is unavailable. Make sure it is not region or age restricted, and is not private."),
-            "error.api.content.post.private" => f.write_str("That post is private."),
-            "error.api.content.post.age" => f.write_str("That post is age restricted."),
-            "error.api.youtube.codec" => f.write_str("Missing YouTube codec. This is a bug."),
-            "error.api.youtube.decipher" => f.write_str("Cannot decipher that video. Something probably broke."),
-            "error.api.youtube.login" => f.write_str("That video requires a logged in account, which we do not have."),
-            "error.api.youtube.token_expired" => f.write_str("Our YouTube token expired (try again later)"),
-            "error.api.youtube.temporary_disabled" => f.write_str("YouTube support is temporarily disabled. Try again later."),
-            _ => f.write_str(&self.code),
+        f.write_str(&self.message_with(&English))
+    }
+}
+
+impl Error for CobaltError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn Error + 'static))
+    }
+}
+
+/// Error returned by [`ClientBuilder::build`](crate::ClientBuilder::build) when the client is misconfigured.
+#[derive(Debug)]
+pub enum BuildError {
+    /// No base URL was set.
+    MissingBaseUrl,
+    /// The base URL could not be parsed.
+    InvalidBaseUrl(url::ParseError),
+    /// Neither an API key nor a bearer token was set, and `no_api_key` is false.
+    MissingCredentials,
+    /// Both an API key and a bearer token were set.
+    ConflictingCredentials,
+    /// The default HTTP client could not be created.
+    HttpClient(reqwest::Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingBaseUrl => f.write_str("base_url is required"),
+            BuildError::InvalidBaseUrl(err) => write!(f, "invalid base_url: {err}"),
+            BuildError::MissingCredentials => {
+                f.write_str("must set either api_key or bearer_token")
+            }
+            BuildError::ConflictingCredentials => {
+                f.write_str("cannot set both api_key and bearer_token")
+            }
+            BuildError::HttpClient(err) => write!(f, "failed to build the HTTP client: {err}"),
         }
     }
 }
 
-impl std::error::Error for CobaltError {}
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::InvalidBaseUrl(err) => Some(err),
+            BuildError::HttpClient(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -61,19 +445,91 @@ mod tests {
 
     #[test]
     fn test_error_display() {
-        let error = CobaltError {
-            code: "error.api.unreachable".to_string(),
-            context: None,
-        };
+        let error = CobaltError::new("error.api.unreachable");
         assert_eq!(format!("{}", error), "API unreachable (try again later)");
     }
 
     #[test]
     fn test_error_unknown() {
-        let error = CobaltError {
-            code: "error.api.unknown".to_string(),
-            context: None,
-        };
+        let error = CobaltError::new("error.api.unknown");
         assert_eq!(format!("{}", error), "error.api.unknown");
     }
+
+    #[test]
+    fn test_error_kind() {
+        let error = CobaltError::new("error.api.Content.Video.Private");
+        assert_eq!(error.kind(), CobaltErrorKind::VideoPrivate);
+        assert!(error.kind().is_user_error());
+        assert!(!error.kind().is_retryable());
+
+        assert!(CobaltErrorKind::from_code("error.api.capacity").is_retryable());
+        assert!(CobaltErrorKind::from_code("error.api.youtube.login").is_service_error());
+        assert_eq!(
+            CobaltErrorKind::from_code("error.api.new_code"),
+            CobaltErrorKind::Unknown("error.api.new_code".to_string())
+        );
+        assert_eq!(
+            CobaltErrorKind::RateExceeded.as_code(),
+            "error.api.rate_exceeded"
+        );
+    }
+
+    #[test]
+    fn test_error_source_and_body() {
+        let source = serde_json::from_str::<u32>("oops").unwrap_err();
+        let error = CobaltError::new("error.api.unknown_response")
+            .with_status(reqwest::StatusCode::BAD_GATEWAY)
+            .with_body(&"é".repeat(300))
+            .with_source(source);
+
+        assert_eq!(error.status, Some(502));
+        assert_eq!(error.body.as_ref().unwrap().len(), 512);
+        assert!(error.source().unwrap().is::<serde_json::Error>());
+        assert_eq!(
+            format!("{}", error),
+            "Download failure. Make sure the link is valid. (unknown response)"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "30".parse().unwrap());
+        headers.insert(RATELIMIT_REMAINING, "0".parse().unwrap());
+        headers.insert(RATELIMIT_RESET, "45".parse().unwrap());
+
+        let rate_limit = RateLimit::from_headers(&headers).unwrap();
+        assert_eq!(rate_limit.retry_after, Some(Duration::from_secs(30)));
+        assert_eq!(rate_limit.reset, Some(Duration::from_secs(45)));
+        assert_eq!(rate_limit.remaining, Some(0));
+
+        let error = CobaltError::new("error.api.rate_exceeded").with_rate_limit(rate_limit);
+        assert_eq!(error.context.unwrap().limit, Some(30));
+
+        // the limiter sends the window reset on every response
+        let mut headers = HeaderMap::new();
+        headers.insert(RATELIMIT_REMAINING, "4".parse().unwrap());
+        headers.insert(RATELIMIT_RESET, "45".parse().unwrap());
+        let rate_limit = RateLimit::from_headers(&headers).unwrap();
+
+        let error = CobaltError::new("error.api.capacity").with_rate_limit(rate_limit);
+        assert!(error.context.is_none());
+        assert_eq!(error.requested_wait(), None);
+    }
+
+    #[test]
+    fn test_rate_limit_combined_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            RATELIMIT,
+            "limit=20, remaining=3, reset=12".parse().unwrap(),
+        );
+
+        let rate_limit = RateLimit::from_headers(&headers).unwrap();
+        assert_eq!(rate_limit.limit, Some(20));
+        assert_eq!(rate_limit.remaining, Some(3));
+        assert_eq!(rate_limit.wait_time(), Some(Duration::from_secs(12)));
+
+        assert_eq!(RateLimit::from_headers(&HeaderMap::new()), None);
+    }
 }
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, PoisonError, RwLock};
+
+use super::error::{CobaltError, ErrorContext};
+
+/// Source of human-readable messages for error codes.
+///
+/// Messages are looked up by error code, e.g. `error.api.link.invalid`. A message can have
+/// variants for when the error carries context: `<code>+limit` is used when
+/// `ErrorContext.limit` is set, `<code>+size` when `ErrorContext.size` is set and
+/// `<code>+service` when `ErrorContext.service` is set, before falling back to `<code>`.
+/// The `{service}`, `{limit}` and `{size}` placeholders are replaced with the context values.
+pub trait MessageCatalog: Send + Sync {
+    /// Returns the message template for the given key, if the catalog has one.
+    fn template(&self, key: &str) -> Option<&str>;
+}
+
+/// The built-in English messages, used by the `Display` implementation of [`CobaltError`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct English;
+
+#[rustfmt::skip]
+const ENGLISH: &[(&str, &str)] = &[
+    ("error.api.unreachable", "API unreachable (try again later)"),
+    ("error.api.timed_out", "API timeout (try again later)"),
+    ("error.api.rate_exceeded", "Rate limited (try again later)"),
+    ("error.api.rate_exceeded+limit", "Rate limited (try again in {limit} seconds)"),
+    ("error.api.capacity", "API busy (try again later)"),
+    ("error.api.generic", "General API error (try again later)"),
+    ("error.api.unknown_response", "Download failure. Make sure the link is valid. (unknown response)"),
+    ("error.api.service.unsupported", "That service or website is not supported."),
+    ("error.api.service.unsupported+service", "Downloading from {service} is not supported."),
+    ("error.api.service.disabled", "Downloading from that service or website is temporarily disabled."),
+    ("error.api.service.disabled+service", "Downloading from {service} is temporarily disabled."),
+    ("error.api.link.invalid", "That link is invalid. Make sure it is correct."),
+    ("error.api.link.unsupported", "That link or format is unsupported."),
+    ("error.api.fetch.fail", "Failed to fetch the media. Make sure the link is valid, or try again later."),
+    ("error.api.fetch.critical", "Critical error fetching the media. Make sure the link is valid, or try again later."),
+    ("error.api.fetch.empty", "The service or website returned no data. This may be caused by the site blocking the downloader (try again later)"),
+    ("error.api.fetch.rate", "The service or website has rate limited the downloader (try again later)"),
+    ("error.api.fetch.short_link", "Unable to resolve the shortlink. Try using the full link to the media."),
+    ("error.api.content.too_long", "The requested content is too big."),
+    ("error.api.content.too_long+limit", "The requested content is longer than {limit} seconds."),
+    ("error.api.content.too_large", "The file is too large."),
+    ("error.api.content.too_large+size", "The file is too large ({size} bytes)."),
+    ("error.api.cancelled", "The download was cancelled."),
+    ("error.api.content.video.unavailable", "That video is unavailable. Make sure it is not region or age restricted, and is not private."),
+    ("error.api.content.video.live", "Live videos are unsupported."),
+    ("error.api.content.video.private", "That video is private."),
+    ("error.api.content.video.age", "That video is age restricted."),
+    ("error.api.content.video.region", "That video is region restricted."),
+    ("error.api.content.post.unavailable", "That post is unavailable. Make sure it is not region or age restricted, and is not private."),
+    ("error.api.content.post.private", "That post is private."),
+    ("error.api.content.post.age", "That post is age restricted."),
+    ("error.api.youtube.codec", "Missing YouTube codec. This is a bug."),
+    ("error.api.youtube.decipher", "Cannot decipher that video. Something probably broke."),
+    ("error.api.youtube.login", "That video requires a logged in account, which we do not have."),
+    ("error.api.youtube.token_expired", "Our YouTube token expired (try again later)"),
+    ("error.api.youtube.temporary_disabled", "YouTube support is temporarily disabled. Try again later."),
+    ("error.api.invalid_url", "The instance returned an invalid download link."),
+    ("error.api.no_download_url", "The instance returned no download link."),
+    ("error.api.head_request_failed", "Failed to get the file size (try again later)"),
+    ("error.api.download_failed", "Failed to download the file (try again later)"),
+    ("error.api.save_failed", "Failed to save the file."),
+    ("error.api.not_picker", "That link does not lead to a post with several items."),
+    ("error.api.not_local_processing", "That link does not need to be processed locally."),
+    ("error.api.processing.unavailable", "ffmpeg is not available to process the file."),
+    ("error.api.processing.failed", "Failed to process the file."),
+    ("error.api.processing.unsupported", "The file cannot be processed without ffmpeg."),
+];
+
+impl MessageCatalog for English {
+    fn template(&self, key: &str) -> Option<&str> {
+        ENGLISH
+            .iter()
+            .find(|(code, _)| *code == key)
+            .map(|(_, message)| *message)
+    }
+}
+
+/// A catalog backed by a map of keys to message templates, e.g. loaded from a JSON file.
+///
+/// ```
+/// use ccobalt::model::messages::TableCatalog;
+///
+/// let catalog = TableCatalog::from_json(r#"{
+///     "error.api.link.invalid": "Ссылка недействительна.",
+///     "error.api.service.disabled+service": "Загрузка с {service} временно отключена."
+/// }"#).unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TableCatalog {
+    messages: HashMap<String, String>,
+}
+
+impl TableCatalog {
+    /// Creates a catalog from a map of keys to message templates.
+    pub fn new(messages: HashMap<String, String>) -> Self {
+        Self { messages }
+    }
+
+    /// Parses a catalog from a flat JSON object of keys to message templates.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json).map(Self::new)
+    }
+
+    /// Adds or replaces a message template.
+    pub fn insert(&mut self, key: impl Into<String>, template: impl Into<String>) {
+        self.messages.insert(key.into(), template.into());
+    }
+}
+
+impl MessageCatalog for TableCatalog {
+    fn template(&self, key: &str) -> Option<&str> {
+        self.messages.get(key).map(String::as_str)
+    }
+}
+
+static CATALOGS: LazyLock<RwLock<HashMap<String, Arc<dyn MessageCatalog>>>> =
+    LazyLock::new(Default::default);
+
+/// Registers a catalog for the given locale, e.g. `ru` or `uk`, replacing any previous one.
+pub fn register_catalog(locale: &str, catalog: Arc<dyn MessageCatalog>) {
+    CATALOGS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(locale.to_ascii_lowercase(), catalog);
+}
+
+/// Returns the catalog registered for the given locale.
+///
+/// A region-specific locale such as `uk-UA` falls back to its language, `uk`.
+pub fn catalog(locale: &str) -> Option<Arc<dyn MessageCatalog>> {
+    let locale = locale.to_ascii_lowercase();
+    let catalogs = CATALOGS.read().unwrap_or_else(PoisonError::into_inner);
+
+    catalogs.get(&locale).cloned().or_else(|| {
+        let language = locale.split(['-', '_']).next()?;
+        catalogs.get(language).cloned()
+    })
+}
+
+/// Renders the message for an error from the given catalog, or `None` if it has none.
+pub fn render(catalog: &dyn MessageCatalog, error: &CobaltError) -> Option<String> {
+    let code = error.code.to_ascii_lowercase();
+    let context = error.context.as_ref();
+
+    let limit = context.and_then(|context| context.limit);
+    let size = context.and_then(|context| context.size);
+    let service = context.and_then(|context| context.service.as_deref());
+
+    let template = limit
+        .and_then(|_| catalog.template(&format!("{code}+limit")))
+        .or_else(|| size.and_then(|_| catalog.template(&format!("{code}+size"))))
+        .or_else(|| service.and_then(|_| catalog.template(&format!("{code}+service"))))
+        .or_else(|| catalog.template(&code))?;
+
+    Some(interpolate(template, context))
+}
+
+fn interpolate(template: &str, context: Option<&ErrorContext>) -> String {
+    let mut message = template.to_string();
+
+    if let Some(context) = context {
+        if let Some(service) = &context.service {
+            message = message.replace("{service}", service);
+        }
+        if let Some(limit) = context.limit {
+            message = message.replace("{limit}", &limit.to_string());
+        }
+        if let Some(size) = context.size {
+            message = message.replace("{size}", &size.to_string());
+        }
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(code: &str, service: Option<&str>, limit: Option<u32>) -> CobaltError {
+        CobaltError::new(code).with_context(ErrorContext {
+            service: service.map(str::to_string),
+            limit,
+            size: None,
+        })
+    }
+
+    #[test]
+    fn test_english_context() {
+        let disabled = error("error.api.service.disabled", Some("youtube"), None);
+        assert_eq!(
+            render(&English, &disabled).unwrap(),
+            "Downloading from youtube is temporarily disabled."
+        );
+
+        let too_long = error("error.api.content.too_long", None, Some(600));
+        assert_eq!(
+            render(&English, &too_long).unwrap(),
+            "The requested content is longer than 600 seconds."
+        );
+
+        let too_large =
+            CobaltError::new("error.api.content.too_large").with_context(ErrorContext {
+                size: Some(62_914_560),
+                ..ErrorContext::default()
+            });
+        assert_eq!(
+            render(&English, &too_large).unwrap(),
+            "The file is too large (62914560 bytes)."
+        );
+
+        let without_context = CobaltError::new("error.api.content.too_long");
+        assert_eq!(
+            render(&English, &without_context).unwrap(),
+            "The requested content is too big."
+        );
+    }
+
+    #[test]
+    fn test_registered_catalog() {
+        let catalog = TableCatalog::from_json(
+            r#"{"error.api.service.disabled+service": "Завантаження з {service} тимчасово вимкнено."}"#,
+        )
+        .unwrap();
+        register_catalog("uk", Arc::new(catalog));
+
+        let disabled = error("error.api.service.disabled", Some("tiktok"), None);
+        assert_eq!(
+            disabled.message("uk-UA"),
+            "Завантаження з tiktok тимчасово вимкнено."
+        );
+        // falls back to English for missing keys and unknown locales
+        assert_eq!(
+            CobaltError::new("error.api.capacity").message("uk"),
+            "API busy (try again later)"
+        );
+        assert_eq!(
+            CobaltError::new("error.api.capacity").message("fr"),
+            "API busy (try again later)"
+        );
+    }
+}
@@ -1,3 +1,4 @@
 pub mod error;
+pub mod messages;
 pub mod request;
 pub mod response;
@@ -103,7 +103,7 @@ pub struct Output {
     pub metadata: Option<OutputMetadata>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct OutputMetadata {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub album: Option<String>,
@@ -135,7 +135,7 @@ pub struct PickerItem {
     pub thumb: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LocalProcessingKind {
     Merge,
@@ -0,0 +1,24 @@
+use tokio_util::sync::CancellationToken;
+
+use crate::util::progress::ProgressSender;
+use crate::util::write::Collision;
+
+/// Per-download options for the `*_with` methods of [`Client`](crate::Client).
+#[derive(Debug, Default, Clone)]
+pub struct DownloadOptions {
+    /// Receives progress updates while the file is downloaded.
+    ///
+    /// Create one with [`util::progress::channel`](crate::util::progress::channel).
+    pub progress: Option<ProgressSender>,
+    /// Maximum number of picker items downloaded at the same time, 4 if not set.
+    pub concurrency: Option<usize>,
+    /// What to do when a file with the same name already exists, overwrite by default.
+    pub collision: Collision,
+    /// Largest file that is downloaded, in bytes, overriding
+    /// [`ClientBuilder::max_bytes`](crate::ClientBuilder::max_bytes).
+    pub max_bytes: Option<u64>,
+    /// Stops the download with `error.api.cancelled` when cancelled, removing partial files.
+    ///
+    /// Data already written to a caller-provided writer is left as is.
+    pub cancel: Option<CancellationToken>,
+}
@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use crate::util::filetype::Type;
+
+/// Number of picker items downloaded at the same time when not set in the options.
+pub(crate) const DEFAULT_CONCURRENCY: usize = 4;
+
+/// A downloaded item of a picker response.
+#[derive(Debug)]
+pub struct PickerDownload {
+    /// Item kind as sent by cobalt: `photo`, `video` or `gif`, or `audio` for the audio track.
+    pub kind: String,
+    pub bytes: Vec<u8>,
+    /// File type detected from the first bytes of the item.
+    pub file_type: Option<Type>,
+}
+
+/// All items of a picker response, in the order cobalt sent them.
+#[derive(Debug)]
+pub struct PickerDownloads {
+    pub items: Vec<PickerDownload>,
+    /// The audio track sent along with the items, e.g. for TikTok slideshows.
+    pub audio: Option<PickerDownload>,
+}
+
+/// An item of a picker response saved to disk.
+#[derive(Debug)]
+pub struct SavedPickerItem {
+    /// Item kind as sent by cobalt: `photo`, `video` or `gif`, or `audio` for the audio track.
+    pub kind: String,
+    pub path: PathBuf,
+}
+
+/// All saved items of a picker response, in the order cobalt sent them.
+#[derive(Debug)]
+pub struct SavedPicker {
+    pub items: Vec<SavedPickerItem>,
+    /// The audio track sent along with the items, e.g. for TikTok slideshows.
+    pub audio: Option<SavedPickerItem>,
+}
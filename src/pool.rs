@@ -0,0 +1,363 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use reqwest::Url;
+use tokio::task::JoinHandle;
+
+use crate::Client;
+use crate::model::error::{CobaltError, CobaltErrorKind};
+use crate::model::request::DownloadRequest;
+use crate::model::response::DownloadResponse;
+
+/// Errors after which the pool moves on to the next instance.
+const FAILOVER_KINDS: &[CobaltErrorKind] = &[
+    CobaltErrorKind::Capacity,
+    CobaltErrorKind::Unreachable,
+    CobaltErrorKind::ServiceDisabled,
+    CobaltErrorKind::ServiceUnsupported,
+];
+
+/// Errors that mark an instance as unhealthy until the next health check.
+const UNHEALTHY_KINDS: &[CobaltErrorKind] =
+    &[CobaltErrorKind::Capacity, CobaltErrorKind::Unreachable];
+
+/// How the pool picks an instance for each request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Cycle through the healthy instances.
+    #[default]
+    RoundRobin,
+    /// Prefer the healthy instance that answered the last health check fastest.
+    LeastLatency,
+}
+
+/// Health of a single instance in an [`InstancePool`].
+#[derive(Debug, Clone)]
+pub struct InstanceStatus {
+    pub base_url: Url,
+    pub healthy: bool,
+    /// Response time of the last successful health check.
+    pub latency: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Instance {
+    client: Client,
+    healthy: AtomicBool,
+    /// Latency in microseconds, `u64::MAX` while unknown.
+    latency: AtomicU64,
+}
+
+impl Instance {
+    fn latency(&self) -> Option<Duration> {
+        match self.latency.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    instances: Vec<Instance>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+/// A set of cobalt instances with health checks and failover.
+///
+/// Each instance is a [`Client`] with its own base URL and credentials. Requests are
+/// routed to a healthy instance according to the [`Strategy`], and move on to the next
+/// instance when one is busy, unreachable or does not support the service.
+///
+/// Health checks cache the services of every instance, so instances that do not
+/// support the service of a request are skipped without a round trip.
+///
+/// Cloning the pool is cheap, all clones share the same instances and health state.
+#[derive(Debug, Clone)]
+pub struct InstancePool {
+    inner: Arc<PoolInner>,
+}
+
+#[derive(Debug, Default)]
+pub struct InstancePoolBuilder {
+    clients: Vec<Client>,
+    strategy: Strategy,
+}
+
+impl InstancePoolBuilder {
+    /// Creates a new `InstancePoolBuilder` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an instance to the pool.
+    ///
+    /// Instances are tried in the order they were added when round-robin is not possible,
+    /// so a public fallback instance should be added last.
+    pub fn instance(mut self, client: Client) -> Self {
+        self.clients.push(client);
+        self
+    }
+
+    /// Sets how the pool picks an instance for each request.
+    ///
+    /// If not set, `Strategy::RoundRobin` is used.
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Builds the `InstancePool` instance.
+    ///
+    /// All instances are considered healthy until a health check or request says otherwise.
+    pub fn build(self) -> InstancePool {
+        let instances = self
+            .clients
+            .into_iter()
+            .map(|client| Instance {
+                client,
+                healthy: AtomicBool::new(true),
+                latency: AtomicU64::new(u64::MAX),
+            })
+            .collect();
+
+        InstancePool {
+            inner: Arc::new(PoolInner {
+                instances,
+                strategy: self.strategy,
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+impl InstancePool {
+    /// Creates a new `InstancePoolBuilder` to configure and build an `InstancePool`.
+    pub fn builder() -> InstancePoolBuilder {
+        InstancePoolBuilder::new()
+    }
+
+    /// Returns the current health of every instance, in the order they were added.
+    pub fn status(&self) -> Vec<InstanceStatus> {
+        self.inner
+            .instances
+            .iter()
+            .map(|instance| InstanceStatus {
+                base_url: instance.client.base_url().clone(),
+                healthy: instance.healthy.load(Ordering::Relaxed),
+                latency: instance.latency(),
+            })
+            .collect()
+    }
+
+    /// Calls `get_info` on every instance and updates their health and latency.
+    pub async fn check_health(&self) {
+        join_all(self.inner.instances.iter().map(async |instance| {
+            let started = Instant::now();
+            let healthy = instance.client.get_info().await.is_ok();
+
+            if healthy {
+                let micros = started
+                    .elapsed()
+                    .as_micros()
+                    .try_into()
+                    .unwrap_or(u64::MAX - 1);
+                instance.latency.store(micros, Ordering::Relaxed);
+            }
+            instance.healthy.store(healthy, Ordering::Relaxed);
+        }))
+        .await;
+    }
+
+    /// Spawns a task that runs [`InstancePool::check_health`] every `interval`.
+    ///
+    /// The task runs until the returned handle is aborted.
+    pub fn spawn_health_checks(&self, interval: Duration) -> JoinHandle<()> {
+        let pool = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                pool.check_health().await;
+            }
+        })
+    }
+
+    /// Resolves a download request on a healthy instance, failing over to the next one
+    /// when an instance is busy, unreachable or does not support the service.
+    pub async fn resolve_download(
+        &self,
+        request: &DownloadRequest,
+    ) -> Result<DownloadResponse, CobaltError> {
+        self.failover(
+            async |client| client.resolve_download(request).await,
+            |result| match result {
+                Ok(DownloadResponse::Error { error }) | Err(error) => Some(error),
+                Ok(_) => None,
+            },
+        )
+        .await
+    }
+
+    /// Runs `op` with the client of a healthy instance, failing over to the next one
+    /// when an instance is busy, unreachable or does not support the service.
+    ///
+    /// Useful to run any [`Client`] method through the pool:
+    ///
+    /// ```no_run
+    /// # async fn example(pool: ccobalt::InstancePool, request: ccobalt::model::request::DownloadRequest) {
+    /// let bytes = pool.execute(async |client| client.download(&request).await).await;
+    /// # }
+    /// ```
+    pub async fn execute<T>(
+        &self,
+        op: impl AsyncFn(&Client) -> Result<T, CobaltError>,
+    ) -> Result<T, CobaltError> {
+        self.failover(op, |result| result.as_ref().err()).await
+    }
+
+    /// Runs `op` on each instance in turn until `error_of` returns no error, or an error
+    /// that is not worth failing over for.
+    #[allow(clippy::result_large_err)]
+    async fn failover<T>(
+        &self,
+        op: impl AsyncFn(&Client) -> Result<T, CobaltError>,
+        error_of: impl Fn(&Result<T, CobaltError>) -> Option<&CobaltError>,
+    ) -> Result<T, CobaltError> {
+        let mut last = None;
+
+        for index in self.order() {
+            let instance = &self.inner.instances[index];
+            let result = op(&instance.client).await;
+
+            let Some(error) = error_of(&result).filter(|error| has_kind(error, FAILOVER_KINDS))
+            else {
+                instance.healthy.store(true, Ordering::Relaxed);
+                return result;
+            };
+
+            if has_kind(error, UNHEALTHY_KINDS) {
+                instance.healthy.store(false, Ordering::Relaxed);
+            }
+            last = Some(result);
+        }
+
+        last.unwrap_or_else(|| Err(CobaltError::new("error.api.unreachable")))
+    }
+
+    /// Returns the instance indices in the order they should be tried.
+    ///
+    /// Healthy instances come first, unhealthy ones are kept as a last resort. Round robin
+    /// rotates over the healthy instances only, so the share of an unhealthy one is spread
+    /// evenly instead of going to its neighbour.
+    fn order(&self) -> Vec<usize> {
+        let instances = &self.inner.instances;
+        if instances.is_empty() {
+            return Vec::new();
+        }
+
+        let next = self.inner.next.fetch_add(1, Ordering::Relaxed);
+        let healthy = |index: &usize| instances[*index].healthy.load(Ordering::Relaxed);
+
+        match self.inner.strategy {
+            Strategy::RoundRobin => {
+                let (mut order, mut unhealthy): (Vec<usize>, Vec<usize>) =
+                    (0..instances.len()).partition(healthy);
+                for group in [&mut order, &mut unhealthy] {
+                    if !group.is_empty() {
+                        let len = group.len();
+                        group.rotate_left(next % len);
+                    }
+                }
+
+                order.append(&mut unhealthy);
+                order
+            }
+            Strategy::LeastLatency => {
+                let start = next % instances.len();
+                let mut order: Vec<usize> = (0..instances.len())
+                    .map(|offset| (start + offset) % instances.len())
+                    .collect();
+                order.sort_by_key(|index| {
+                    (
+                        !healthy(index),
+                        instances[*index].latency.load(Ordering::Relaxed),
+                    )
+                });
+                order
+            }
+        }
+    }
+}
+
+fn has_kind(error: &CobaltError, kinds: &[CobaltErrorKind]) -> bool {
+    kinds.contains(&error.kind())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: Strategy) -> InstancePool {
+        let client = |url: &str| {
+            Client::builder()
+                .base_url(url)
+                .no_api_key(true)
+                .build()
+                .unwrap()
+        };
+
+        InstancePool::builder()
+            .instance(client("http://127.0.0.1:1/a"))
+            .instance(client("http://127.0.0.1:1/b"))
+            .instance(client("http://127.0.0.1:1/c"))
+            .strategy(strategy)
+            .build()
+    }
+
+    #[test]
+    fn test_round_robin_skips_unhealthy() {
+        let pool = pool(Strategy::RoundRobin);
+        pool.inner.instances[1]
+            .healthy
+            .store(false, Ordering::Relaxed);
+
+        assert_eq!(pool.order(), vec![0, 2, 1]);
+        assert_eq!(pool.order(), vec![2, 0, 1]);
+        assert_eq!(pool.order(), vec![0, 2, 1]);
+        assert_eq!(pool.order(), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_least_latency() {
+        let pool = pool(Strategy::LeastLatency);
+        pool.inner.instances[0]
+            .latency
+            .store(300, Ordering::Relaxed);
+        pool.inner.instances[1]
+            .latency
+            .store(100, Ordering::Relaxed);
+        pool.inner.instances[2]
+            .latency
+            .store(200, Ordering::Relaxed);
+
+        assert_eq!(pool.order(), vec![1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_failover_marks_unreachable() {
+        let pool = pool(Strategy::RoundRobin);
+        let request = DownloadRequest {
+            url: "https://example.com".to_string(),
+            ..Default::default()
+        };
+
+        let error = pool.resolve_download(&request).await.unwrap_err();
+        assert_eq!(error.code, "error.api.unreachable");
+        assert!(pool.status().iter().all(|status| !status.healthy));
+    }
+}
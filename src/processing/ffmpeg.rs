@@ -0,0 +1,195 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use tokio::process::Command;
+
+use super::{ProcessingError, ProcessingJob};
+use crate::model::response::LocalProcessingKind;
+
+/// Number of bytes of ffmpeg's stderr kept in a [`ProcessingError::Failed`].
+const STDERR_LIMIT: usize = 2048;
+
+/// Runs local processing jobs through an ffmpeg binary.
+#[derive(Debug, Clone)]
+pub struct Ffmpeg {
+    path: PathBuf,
+}
+
+impl Default for Ffmpeg {
+    fn default() -> Self {
+        Self::new("ffmpeg")
+    }
+}
+
+impl Ffmpeg {
+    /// Creates a runner for the given binary, either a path or a name looked up in `PATH`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Returns the path of the ffmpeg binary.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Runs the job on the downloaded `inputs`, writing the result to `output`.
+    ///
+    /// `inputs` must be in the same order as the job's tunnel URLs.
+    pub async fn run(
+        &self,
+        job: &ProcessingJob,
+        inputs: &[PathBuf],
+        output: &Path,
+    ) -> Result<(), ProcessingError> {
+        if inputs.len() < job.input_count() {
+            return Err(ProcessingError::MissingInput);
+        }
+
+        let result = Command::new(&self.path)
+            .args(args(job, inputs, output))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(ProcessingError::Spawn)?;
+
+        if result.status.success() {
+            // ffmpeg does not sync its output, do it before the file is renamed into place
+            return tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(output)
+                .await?
+                .sync_all()
+                .await
+                .map_err(Into::into);
+        }
+
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        let mut start = stderr.len().saturating_sub(STDERR_LIMIT);
+        while !stderr.is_char_boundary(start) {
+            start += 1;
+        }
+
+        Err(ProcessingError::Failed {
+            status: result.status,
+            stderr: stderr[start..].trim().to_string(),
+        })
+    }
+}
+
+/// Builds the ffmpeg arguments for a job, matching what the cobalt web UI runs.
+///
+/// Metadata is written afterwards by [`util::tags`](crate::util::tags).
+pub(crate) fn args(job: &ProcessingJob, inputs: &[PathBuf], output: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = ["-hide_banner", "-loglevel", "error", "-nostdin", "-y"]
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+
+    for input in inputs.iter().take(job.input_count()) {
+        args.push("-i".into());
+        args.push(input.into());
+    }
+
+    let mut push = |values: &[&str]| args.extend(values.iter().map(OsString::from));
+
+    match job.kind {
+        LocalProcessingKind::Merge => {
+            push(&["-map", "0:v", "-map", "1:a", "-c:v", "copy", "-c:a", "copy"])
+        }
+        LocalProcessingKind::Remux => push(&["-c", "copy"]),
+        LocalProcessingKind::Mute => push(&["-c", "copy", "-an"]),
+        LocalProcessingKind::Gif => push(&[
+            "-vf",
+            "scale=-1:-1:flags=lanczos,split[s0][s1];[s0]palettegen[p];[s1][p]paletteuse",
+            "-loop",
+            "0",
+        ]),
+        LocalProcessingKind::Audio => {
+            push(&["-vn"]);
+
+            match &job.audio {
+                Some(audio) if audio.copy => push(&["-c:a", "copy"]),
+                Some(audio) => {
+                    push(&["-b:a", &format!("{}k", audio.bitrate)]);
+
+                    // the lowest bitrate only sounds right at a lower sample rate
+                    if audio.bitrate == "8" && matches!(audio.format.as_str(), "mp3" | "opus") {
+                        push(&["-ar", "12000"]);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    if matches!(
+        job.kind,
+        LocalProcessingKind::Merge | LocalProcessingKind::Remux | LocalProcessingKind::Mute
+    ) && job.output.mime_type.starts_with("video/mp4")
+    {
+        args.push("-movflags".into());
+        args.push("+faststart".into());
+    }
+
+    args.push(output.into());
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::response::{Audio, Output};
+
+    fn job(kind: LocalProcessingKind, mime_type: &str, audio: Option<Audio>) -> ProcessingJob {
+        ProcessingJob {
+            kind,
+            service: "youtube".to_string(),
+            tunnel: vec!["https://a".to_string(), "https://b".to_string()],
+            output: Output {
+                mime_type: mime_type.to_string(),
+                filename: "video.mp4".to_string(),
+                metadata: None,
+            },
+            audio,
+        }
+    }
+
+    fn joined(args: Vec<OsString>) -> String {
+        args.iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_merge_args() {
+        let job = job(LocalProcessingKind::Merge, "video/mp4", None);
+        let inputs = [PathBuf::from("v"), PathBuf::from("a")];
+
+        assert_eq!(
+            joined(args(&job, &inputs, Path::new("out.mp4"))),
+            "-hide_banner -loglevel error -nostdin -y -i v -i a \
+             -map 0:v -map 1:a -c:v copy -c:a copy -movflags +faststart out.mp4"
+        );
+    }
+
+    #[test]
+    fn test_audio_args() {
+        let audio = Audio {
+            copy: false,
+            format: "mp3".to_string(),
+            bitrate: "8".to_string(),
+        };
+        let job = job(LocalProcessingKind::Audio, "audio/mpeg", Some(audio));
+
+        let args = joined(args(&job, &[PathBuf::from("in")], Path::new("out.mp3")));
+        assert_eq!(
+            args,
+            "-hide_banner -loglevel error -nostdin -y -i in -vn -b:a 8k -ar 12000 out.mp3"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+
+use crate::model::response::{Audio, DownloadResponse, LocalProcessingKind, Output};
+
+pub mod ffmpeg;
+#[cfg(feature = "mp4")]
+pub mod mp4;
+
+/// A `local-processing` response, describing the work cobalt expects the client to do.
+#[derive(Debug)]
+pub struct ProcessingJob {
+    pub kind: LocalProcessingKind,
+    pub service: String,
+    /// Input URLs, video first for `Merge`.
+    pub tunnel: Vec<String>,
+    pub output: Output,
+    pub audio: Option<Audio>,
+}
+
+impl ProcessingJob {
+    /// Takes the job out of a `local-processing` response, or gives the response back.
+    #[allow(clippy::result_large_err)]
+    pub fn from_response(response: DownloadResponse) -> Result<Self, DownloadResponse> {
+        match response {
+            DownloadResponse::LocalProcessing {
+                kind,
+                service,
+                tunnel,
+                output,
+                audio,
+                ..
+            } => Ok(Self {
+                kind,
+                service,
+                tunnel,
+                output: *output,
+                audio,
+            }),
+            other => Err(other),
+        }
+    }
+
+    /// Returns the number of inputs the job needs.
+    pub fn input_count(&self) -> usize {
+        match self.kind {
+            LocalProcessingKind::Merge => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Error returned when local processing fails.
+#[derive(Debug)]
+pub enum ProcessingError {
+    /// The job has fewer tunnel URLs than its kind needs.
+    MissingInput,
+    /// The processing binary could not be started.
+    Spawn(std::io::Error),
+    /// The processing binary exited with an error.
+    Failed {
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+    /// Reading an input or writing the output failed.
+    Io(std::io::Error),
+    /// An input file is malformed.
+    InvalidInput(String),
+    /// The job or its codecs cannot be handled without ffmpeg.
+    Unsupported(String),
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessingError::MissingInput => f.write_str("not enough tunnel URLs for the job"),
+            ProcessingError::Spawn(err) => write!(f, "failed to start ffmpeg: {err}"),
+            ProcessingError::Failed { status, stderr } => {
+                write!(f, "ffmpeg exited with {status}: {stderr}")
+            }
+            ProcessingError::Io(err) => write!(f, "processing failed: {err}"),
+            ProcessingError::InvalidInput(reason) => write!(f, "invalid input: {reason}"),
+            ProcessingError::Unsupported(reason) => write!(f, "unsupported: {reason}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for ProcessingError {
+    fn from(err: std::io::Error) -> Self {
+        ProcessingError::Io(err)
+    }
+}
+
+impl std::error::Error for ProcessingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProcessingError::Spawn(err) | ProcessingError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
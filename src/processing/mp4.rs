@@ -0,0 +1,1147 @@
+//! Pure-Rust merging and remuxing of ISO-BMFF (MP4) streams.
+//!
+//! Handles the `merge`, `remux` and `mute` jobs when cobalt sends H.264 video and AAC audio
+//! in MP4 containers, both plain and fragmented. The samples are copied as is into a single
+//! MP4 with the `moov` box in front, so no decoding or encoding takes place.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{ProcessingError, ProcessingJob};
+use crate::model::response::LocalProcessingKind;
+use crate::util::bmff::{
+    self, Bytes, Malformed, child, children, put_u16, put_u32, put_u64, require, write_box,
+    write_full_box,
+};
+
+/// Sample entry types that can be copied into the output.
+const VIDEO_CODECS: &[&[u8; 4]] = &[b"avc1", b"avc3"];
+const AUDIO_CODECS: &[&[u8; 4]] = &[b"mp4a"];
+
+/// Timescale of the output movie header.
+const MOVIE_TIMESCALE: u32 = 1000;
+
+/// Target duration of an interleaved chunk, in seconds.
+const CHUNK_SECONDS: f64 = 0.5;
+
+/// Returns whether the job kind can be handled without ffmpeg.
+pub fn supports(kind: &LocalProcessingKind) -> bool {
+    matches!(
+        kind,
+        LocalProcessingKind::Merge | LocalProcessingKind::Remux | LocalProcessingKind::Mute
+    )
+}
+
+/// Runs the job on the downloaded `inputs`, writing the result to `output`.
+///
+/// Returns [`ProcessingError::Unsupported`] when the job kind or the codecs need ffmpeg.
+pub async fn run(
+    job: &ProcessingJob,
+    inputs: &[PathBuf],
+    output: &Path,
+) -> Result<(), ProcessingError> {
+    if inputs.len() < job.input_count() {
+        return Err(ProcessingError::MissingInput);
+    }
+
+    let kind = job.kind;
+    let inputs = inputs.to_vec();
+    let output = output.to_path_buf();
+
+    // the blocking task outlives a cancelled caller, which must not find the output later
+    let abandoned = Abandoned(Arc::new(AtomicBool::new(false)));
+    let flag = Arc::clone(&abandoned.0);
+
+    tokio::task::spawn_blocking(move || {
+        let result = mux(&kind, &inputs, &output);
+        if result.is_err() || flag.load(Ordering::Acquire) {
+            let _ = std::fs::remove_file(&output);
+        }
+        result
+    })
+    .await
+    .map_err(|err| ProcessingError::Io(io::Error::other(err)))?
+}
+
+/// Marks a muxing task as abandoned when the future waiting for it is dropped.
+struct Abandoned(Arc<AtomicBool>);
+
+impl Drop for Abandoned {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// Muxes the inputs of a job into `output`.
+fn mux(
+    kind: &LocalProcessingKind,
+    inputs: &[PathBuf],
+    output: &Path,
+) -> Result<(), ProcessingError> {
+    let tracks = match kind {
+        LocalProcessingKind::Merge => {
+            let video = read_movie(&inputs[0])?;
+            let audio = read_movie(&inputs[1])?;
+            vec![
+                take_track(video, 0, b"vide")?,
+                take_track(audio, 1, b"soun")?,
+            ]
+        }
+        LocalProcessingKind::Remux => {
+            let movie = read_movie(&inputs[0])?;
+            let tracks: Vec<_> = movie
+                .into_iter()
+                .filter(|track| matches!(&track.handler, b"vide" | b"soun"))
+                .map(|track| (0, track))
+                .collect();
+
+            if tracks.is_empty() {
+                return Err(ProcessingError::InvalidInput(
+                    "no audio or video track".to_string(),
+                ));
+            }
+            tracks
+        }
+        LocalProcessingKind::Mute => vec![take_track(read_movie(&inputs[0])?, 0, b"vide")?],
+        kind => {
+            return Err(ProcessingError::Unsupported(format!(
+                "{kind:?} jobs need ffmpeg"
+            )));
+        }
+    };
+
+    for (_, track) in &tracks {
+        check_codec(track)?;
+    }
+
+    let mut sources = inputs
+        .iter()
+        .map(|path| File::open(path).map(BufReader::new))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    write_movie(&tracks, &mut sources, &mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+
+    Ok(())
+}
+
+/// Picks the first track with the given handler.
+fn take_track(
+    tracks: Vec<Track>,
+    input: usize,
+    handler: &[u8; 4],
+) -> Result<(usize, Track), ProcessingError> {
+    tracks
+        .into_iter()
+        .find(|track| &track.handler == handler)
+        .map(|track| (input, track))
+        .ok_or_else(|| {
+            ProcessingError::InvalidInput(format!(
+                "input {input} has no {} track",
+                String::from_utf8_lossy(handler)
+            ))
+        })
+}
+
+fn check_codec(track: &Track) -> Result<(), ProcessingError> {
+    let supported = match &track.handler {
+        b"vide" => VIDEO_CODECS,
+        _ => AUDIO_CODECS,
+    };
+
+    if supported.contains(&&track.codec) {
+        return Ok(());
+    }
+
+    Err(ProcessingError::Unsupported(format!(
+        "{} codec `{}` is not supported, only H.264 video and AAC audio are",
+        if &track.handler == b"vide" {
+            "video"
+        } else {
+            "audio"
+        },
+        String::from_utf8_lossy(&track.codec)
+    )))
+}
+
+/// A single sample (frame) of a track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    /// Absolute offset in the input file.
+    offset: u64,
+    size: u32,
+    duration: u32,
+    /// Composition time offset, `ctts`.
+    cts: i32,
+    sync: bool,
+}
+
+/// An edit list entry, in the timescale of the input movie.
+#[derive(Debug, Clone, Copy)]
+struct Edit {
+    segment_duration: u64,
+    media_time: i64,
+}
+
+#[derive(Debug)]
+struct Track {
+    id: u32,
+    handler: [u8; 4],
+    codec: [u8; 4],
+    /// Payload of the `stsd` box, copied to the output unchanged.
+    stsd: Vec<u8>,
+    timescale: u32,
+    movie_timescale: u32,
+    language: u16,
+    width: u32,
+    height: u32,
+    edits: Vec<Edit>,
+    samples: Vec<Sample>,
+}
+
+impl Track {
+    fn media_duration(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|sample| u64::from(sample.duration))
+            .sum()
+    }
+
+    /// Duration in the output movie timescale, taking the edit list into account.
+    fn movie_duration(&self) -> u64 {
+        if self.edits.is_empty() {
+            rescale(self.media_duration(), self.timescale, MOVIE_TIMESCALE)
+        } else {
+            let total = self.edits.iter().map(|edit| edit.segment_duration).sum();
+            rescale(total, self.movie_timescale, MOVIE_TIMESCALE)
+        }
+    }
+}
+
+/// Default sample values of a fragmented track, from `trex`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Defaults {
+    duration: u32,
+    size: u32,
+    flags: u32,
+}
+
+fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    if from == 0 {
+        return 0;
+    }
+    (u128::from(value) * u128::from(to) / u128::from(from)) as u64
+}
+
+fn invalid(reason: &str) -> ProcessingError {
+    ProcessingError::InvalidInput(reason.to_string())
+}
+
+impl From<Malformed> for ProcessingError {
+    fn from(err: Malformed) -> Self {
+        ProcessingError::InvalidInput(err.0)
+    }
+}
+
+/// Reads the tracks and their sample tables from an MP4 file, without loading `mdat`.
+fn read_movie(path: &Path) -> Result<Vec<Track>, ProcessingError> {
+    let mut file = BufReader::new(File::open(path)?);
+    let length = file.get_ref().metadata()?.len();
+
+    let not_mp4 = || ProcessingError::Unsupported("input is not an MP4 file".to_string());
+
+    let mut header = [0; 8];
+    if length < 8 || file.read_exact(&mut header).is_err() || &header[4..] != b"ftyp" {
+        return Err(not_mp4());
+    }
+
+    let mut moov = None;
+    let mut fragments = Vec::new();
+    for header in bmff::top_level(&mut file, length)? {
+        match &header.kind {
+            b"moov" => moov = Some(header.read_payload(&mut file)?),
+            b"moof" => fragments.push((header.offset, header.read_payload(&mut file)?)),
+            _ => {}
+        }
+    }
+
+    let moov = moov.ok_or_else(|| invalid("missing `moov` box"))?;
+    parse_movie(&moov, &fragments, length)
+}
+
+/// Parses the tracks of a movie. `length` is the size of the input file, which bounds
+/// the number of samples its tables may declare.
+fn parse_movie(
+    moov: &[u8],
+    fragments: &[(u64, Vec<u8>)],
+    length: u64,
+) -> Result<Vec<Track>, ProcessingError> {
+    let mut mvhd = Bytes::new(require(moov, &[b"mvhd"])?);
+    let (version, _) = mvhd.full_box()?;
+    mvhd.skip(if version == 1 { 16 } else { 8 })?;
+    let movie_timescale = mvhd.u32()?;
+
+    let mut defaults = Vec::new();
+    if let Some(mvex) = child(moov, b"mvex")? {
+        for (kind, payload) in children(mvex)? {
+            if &kind == b"trex" {
+                let mut trex = Bytes::new(payload);
+                trex.full_box()?;
+                let id = trex.u32()?;
+                trex.skip(4)?;
+                defaults.push((
+                    id,
+                    Defaults {
+                        duration: trex.u32()?,
+                        size: trex.u32()?,
+                        flags: trex.u32()?,
+                    },
+                ));
+            }
+        }
+    }
+
+    let mut tracks = Vec::new();
+    for (kind, payload) in children(moov)? {
+        if &kind == b"trak" {
+            tracks.push(parse_track(payload, movie_timescale, length)?);
+        }
+    }
+
+    for (moof_offset, moof) in fragments {
+        for (kind, traf) in children(moof)? {
+            if &kind == b"traf" {
+                parse_fragment(traf, *moof_offset, &defaults, &mut tracks)?;
+            }
+        }
+    }
+
+    Ok(tracks)
+}
+
+fn parse_track(trak: &[u8], movie_timescale: u32, length: u64) -> Result<Track, ProcessingError> {
+    let mut tkhd = Bytes::new(require(trak, &[b"tkhd"])?);
+    let (version, _) = tkhd.full_box()?;
+    tkhd.skip(if version == 1 { 16 } else { 8 })?;
+    let id = tkhd.u32()?;
+    let duration_len = if version == 1 { 8 } else { 4 };
+    // reserved, duration, reserved, layer, group, volume, reserved and the matrix
+    tkhd.skip(4 + duration_len + 8 + 8 + 36)?;
+    let width = tkhd.u32()?;
+    let height = tkhd.u32()?;
+
+    let mdia = require(trak, &[b"mdia"])?;
+
+    let mut mdhd = Bytes::new(require(mdia, &[b"mdhd"])?);
+    let (version, _) = mdhd.full_box()?;
+    mdhd.skip(if version == 1 { 16 } else { 8 })?;
+    let timescale = mdhd.u32()?;
+    mdhd.skip(if version == 1 { 8 } else { 4 })?;
+    let language = mdhd.u16()?;
+
+    let mut hdlr = Bytes::new(require(mdia, &[b"hdlr"])?);
+    hdlr.skip(8)?;
+    let handler = hdlr.fourcc()?;
+
+    let elst = match child(trak, b"edts")? {
+        Some(edts) => child(edts, b"elst")?,
+        None => None,
+    };
+
+    let mut edits = Vec::new();
+    if let Some(elst) = elst {
+        let mut elst = Bytes::new(elst);
+        let (version, _) = elst.full_box()?;
+        for _ in 0..elst.u32()? {
+            let (segment_duration, media_time) = if version == 1 {
+                (elst.u64()?, elst.u64()? as i64)
+            } else {
+                (u64::from(elst.u32()?), i64::from(elst.u32()? as i32))
+            };
+            elst.skip(4)?;
+            edits.push(Edit {
+                segment_duration,
+                media_time,
+            });
+        }
+    }
+
+    let stbl = require(mdia, &[b"minf", b"stbl"])?;
+    let stsd = require(stbl, &[b"stsd"])?;
+    let codec = stsd
+        .get(12..16)
+        .and_then(|codec| codec.try_into().ok())
+        .ok_or_else(|| invalid("empty `stsd` box"))?;
+
+    Ok(Track {
+        id,
+        handler,
+        codec,
+        stsd: stsd.to_vec(),
+        timescale,
+        movie_timescale,
+        language,
+        width,
+        height,
+        edits,
+        samples: parse_sample_table(stbl, length)?,
+    })
+}
+
+/// Expands the sample table of a non-fragmented track into individual samples.
+///
+/// The sample count is checked against the `stsz` payload and the file length before
+/// anything is allocated for it, so a corrupt table fails instead of exhausting memory.
+fn parse_sample_table(stbl: &[u8], length: u64) -> Result<Vec<Sample>, ProcessingError> {
+    let Some(stsz) = child(stbl, b"stsz")? else {
+        return Ok(Vec::new());
+    };
+
+    let mut stsz = Bytes::new(stsz);
+    stsz.full_box()?;
+    let fixed_size = stsz.u32()?;
+    let count = stsz.u32()? as usize;
+    if fixed_size == 0 && count.saturating_mul(4) > stsz.remaining() {
+        return Err(invalid("sample count exceeds the `stsz` box"));
+    }
+    if fixed_size != 0 && count as u64 * u64::from(fixed_size) > length {
+        return Err(invalid("sample count exceeds the file size"));
+    }
+    let sizes = (0..count)
+        .map(|_| {
+            if fixed_size == 0 {
+                stsz.u32()
+            } else {
+                Ok(fixed_size)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut durations = Vec::with_capacity(count);
+    let mut stts = Bytes::new(require(stbl, &[b"stts"])?);
+    stts.full_box()?;
+    for _ in 0..stts.u32()? {
+        let (run, delta) = (stts.u32()?, stts.u32()?);
+        let run = (run as usize).min(count - durations.len());
+        durations.extend(std::iter::repeat_n(delta, run));
+    }
+
+    let mut offsets = Vec::with_capacity(count);
+    if let Some(ctts) = child(stbl, b"ctts")? {
+        let mut ctts = Bytes::new(ctts);
+        ctts.full_box()?;
+        for _ in 0..ctts.u32()? {
+            let (run, offset) = (ctts.u32()?, ctts.u32()? as i32);
+            let run = (run as usize).min(count - offsets.len());
+            offsets.extend(std::iter::repeat_n(offset, run));
+        }
+    }
+
+    let sync = match child(stbl, b"stss")? {
+        Some(stss) => {
+            let mut stss = Bytes::new(stss);
+            stss.full_box()?;
+            let mut sync = vec![false; count];
+            for _ in 0..stss.u32()? {
+                if let Some(sample) = sync.get_mut((stss.u32()? as usize).wrapping_sub(1)) {
+                    *sample = true;
+                }
+            }
+            sync
+        }
+        None => vec![true; count],
+    };
+
+    let chunk_offsets = match (child(stbl, b"stco")?, child(stbl, b"co64")?) {
+        (Some(stco), _) => {
+            let mut stco = Bytes::new(stco);
+            stco.full_box()?;
+            (0..stco.u32()?)
+                .map(|_| stco.u32().map(u64::from))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        (None, Some(co64)) => {
+            let mut co64 = Bytes::new(co64);
+            co64.full_box()?;
+            (0..co64.u32()?)
+                .map(|_| co64.u64())
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        (None, None) => return Err(invalid("missing `stco` box")),
+    };
+
+    let mut stsc = Bytes::new(require(stbl, &[b"stsc"])?);
+    stsc.full_box()?;
+    let runs = (0..stsc.u32()?)
+        .map(|_| {
+            let first_chunk = stsc.u32()?;
+            let samples_per_chunk = stsc.u32()?;
+            stsc.skip(4)?;
+            Ok((first_chunk, samples_per_chunk))
+        })
+        .collect::<Result<Vec<_>, ProcessingError>>()?;
+
+    let mut samples = Vec::with_capacity(count);
+    for (chunk, chunk_offset) in chunk_offsets.iter().enumerate() {
+        let chunk = chunk as u32 + 1;
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first_chunk, _)| *first_chunk <= chunk)
+            .map_or(0, |(_, samples_per_chunk)| *samples_per_chunk);
+
+        let mut offset = *chunk_offset;
+        for _ in 0..per_chunk {
+            let index = samples.len();
+            if index == count {
+                break;
+            }
+
+            samples.push(Sample {
+                offset,
+                size: sizes[index],
+                duration: durations.get(index).copied().unwrap_or(0),
+                cts: offsets.get(index).copied().unwrap_or(0),
+                sync: sync[index],
+            });
+            offset += u64::from(sizes[index]);
+        }
+    }
+
+    if samples.len() != count {
+        return Err(invalid("sample table does not match the sample count"));
+    }
+
+    Ok(samples)
+}
+
+/// Appends the samples of a `traf` box to its track.
+///
+/// Data offsets are resolved against the explicit base data offset or the start of the
+/// `moof` box, which covers the files produced by common DASH packagers.
+fn parse_fragment(
+    traf: &[u8],
+    moof_offset: u64,
+    defaults: &[(u32, Defaults)],
+    tracks: &mut [Track],
+) -> Result<(), ProcessingError> {
+    let mut tfhd = Bytes::new(require(traf, &[b"tfhd"])?);
+    let (_, flags) = tfhd.full_box()?;
+    let id = tfhd.u32()?;
+
+    let mut track_defaults = defaults
+        .iter()
+        .find(|(track, _)| *track == id)
+        .map(|(_, defaults)| *defaults)
+        .unwrap_or_default();
+
+    let base = if flags & 0x01 != 0 {
+        tfhd.u64()?
+    } else {
+        moof_offset
+    };
+    if flags & 0x02 != 0 {
+        tfhd.skip(4)?;
+    }
+    if flags & 0x08 != 0 {
+        track_defaults.duration = tfhd.u32()?;
+    }
+    if flags & 0x10 != 0 {
+        track_defaults.size = tfhd.u32()?;
+    }
+    if flags & 0x20 != 0 {
+        track_defaults.flags = tfhd.u32()?;
+    }
+
+    let track = tracks
+        .iter_mut()
+        .find(|track| track.id == id)
+        .ok_or_else(|| invalid("fragment for an unknown track"))?;
+
+    let mut offset = base;
+    for (kind, payload) in children(traf)? {
+        if &kind != b"trun" {
+            continue;
+        }
+
+        let mut trun = Bytes::new(payload);
+        let (_, flags) = trun.full_box()?;
+        let count = trun.u32()?;
+
+        if flags & 0x001 != 0 {
+            offset = base
+                .checked_add_signed(i64::from(trun.u32()? as i32))
+                .ok_or_else(|| invalid("negative data offset"))?;
+        }
+        let first_flags = if flags & 0x004 != 0 {
+            Some(trun.u32()?)
+        } else {
+            None
+        };
+
+        for index in 0..count {
+            let duration = if flags & 0x100 != 0 {
+                trun.u32()?
+            } else {
+                track_defaults.duration
+            };
+            let size = if flags & 0x200 != 0 {
+                trun.u32()?
+            } else {
+                track_defaults.size
+            };
+            let sample_flags = if flags & 0x400 != 0 {
+                trun.u32()?
+            } else if index == 0 {
+                first_flags.unwrap_or(track_defaults.flags)
+            } else {
+                track_defaults.flags
+            };
+            // unsigned in version 0, but writers put signed values there as well
+            let cts = if flags & 0x800 != 0 {
+                trun.u32()? as i32
+            } else {
+                0
+            };
+
+            track.samples.push(Sample {
+                offset,
+                size,
+                duration,
+                cts,
+                // sample_is_non_sync_sample
+                sync: sample_flags & 0x0001_0000 == 0,
+            });
+            offset += u64::from(size);
+        }
+    }
+
+    Ok(())
+}
+
+/// A run of consecutive samples of one track written together.
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    track: usize,
+    first: usize,
+    count: usize,
+    /// Decode time of the first sample, in seconds.
+    start: f64,
+}
+
+/// Splits every track into chunks and orders them by decode time, so the tracks are
+/// interleaved in the output.
+fn interleave(tracks: &[(usize, Track)]) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+
+    for (index, (_, track)) in tracks.iter().enumerate() {
+        let timescale = f64::from(track.timescale.max(1));
+        let mut time = 0u64;
+        let mut chunk: Option<Chunk> = None;
+
+        for (sample_index, sample) in track.samples.iter().enumerate() {
+            let start = time as f64 / timescale;
+            match &mut chunk {
+                Some(current) if start - current.start < CHUNK_SECONDS => current.count += 1,
+                _ => {
+                    chunks.extend(chunk.take());
+                    chunk = Some(Chunk {
+                        track: index,
+                        first: sample_index,
+                        count: 1,
+                        start,
+                    });
+                }
+            }
+            time += u64::from(sample.duration);
+        }
+
+        chunks.extend(chunk);
+    }
+
+    chunks.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.track.cmp(&b.track)));
+    chunks
+}
+
+fn write_movie<R: Read + Seek, W: Write>(
+    tracks: &[(usize, Track)],
+    sources: &mut [R],
+    writer: &mut W,
+) -> Result<(), ProcessingError> {
+    let chunks = interleave(tracks);
+
+    let mut ftyp = Vec::new();
+    write_box(&mut ftyp, b"ftyp", |out| {
+        out.extend_from_slice(b"isom");
+        out.extend_from_slice(&512u32.to_be_bytes());
+        for brand in [b"isom", b"iso2", b"avc1", b"mp41"] {
+            out.extend_from_slice(brand);
+        }
+    });
+
+    // chunk offsets are 64-bit, so the size of `moov` does not depend on their values
+    let placeholder = vec![Vec::new(); tracks.len()];
+    let moov_len = build_moov(tracks, &chunks, &placeholder).len() as u64;
+
+    let mut offset = ftyp.len() as u64 + moov_len + 16;
+    let mut offsets = vec![Vec::new(); tracks.len()];
+    for chunk in &chunks {
+        offsets[chunk.track].push(offset);
+        offset += chunk_size(&tracks[chunk.track].1, chunk);
+    }
+    let data_len = offset - ftyp.len() as u64 - moov_len;
+
+    writer.write_all(&ftyp)?;
+    writer.write_all(&build_moov(tracks, &chunks, &offsets))?;
+    writer.write_all(&1u32.to_be_bytes())?;
+    writer.write_all(b"mdat")?;
+    writer.write_all(&data_len.to_be_bytes())?;
+
+    for chunk in &chunks {
+        let (input, track) = &tracks[chunk.track];
+        let source = &mut sources[*input];
+
+        // copy runs of samples that are contiguous in the input at once
+        let mut samples = track.samples[chunk.first..chunk.first + chunk.count].iter();
+        let mut run = samples
+            .next()
+            .map(|sample| (sample.offset, u64::from(sample.size)));
+        while let Some((start, len)) = run {
+            run = None;
+            let mut end = start + len;
+            for sample in samples.by_ref() {
+                if sample.offset == end {
+                    end += u64::from(sample.size);
+                } else {
+                    run = Some((sample.offset, u64::from(sample.size)));
+                    break;
+                }
+            }
+
+            source.seek(SeekFrom::Start(start))?;
+            let copied = io::copy(&mut source.by_ref().take(end - start), writer)?;
+            if copied != end - start {
+                return Err(invalid("sample data extends past the end of the file"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn chunk_size(track: &Track, chunk: &Chunk) -> u64 {
+    track.samples[chunk.first..chunk.first + chunk.count]
+        .iter()
+        .map(|sample| u64::from(sample.size))
+        .sum()
+}
+
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn put_matrix(out: &mut Vec<u8>) {
+    for value in MATRIX {
+        put_u32(out, value);
+    }
+}
+
+fn build_moov(tracks: &[(usize, Track)], chunks: &[Chunk], offsets: &[Vec<u64>]) -> Vec<u8> {
+    let duration = tracks
+        .iter()
+        .map(|(_, track)| track.movie_duration())
+        .max()
+        .unwrap_or(0);
+
+    let mut moov = Vec::new();
+    write_box(&mut moov, b"moov", |out| {
+        write_full_box(out, b"mvhd", 1, 0, |out| {
+            put_u64(out, 0);
+            put_u64(out, 0);
+            put_u32(out, MOVIE_TIMESCALE);
+            put_u64(out, duration);
+            put_u32(out, 0x0001_0000);
+            put_u16(out, 0x0100);
+            out.extend_from_slice(&[0; 10]);
+            put_matrix(out);
+            out.extend_from_slice(&[0; 24]);
+            put_u32(out, tracks.len() as u32 + 1);
+        });
+
+        for (index, (_, track)) in tracks.iter().enumerate() {
+            let track_chunks: Vec<&Chunk> =
+                chunks.iter().filter(|chunk| chunk.track == index).collect();
+            write_trak(out, track, index as u32 + 1, &track_chunks, &offsets[index]);
+        }
+    });
+
+    moov
+}
+
+fn write_trak(out: &mut Vec<u8>, track: &Track, id: u32, chunks: &[&Chunk], offsets: &[u64]) {
+    let video = &track.handler == b"vide";
+
+    write_box(out, b"trak", |out| {
+        // enabled and in movie
+        write_full_box(out, b"tkhd", 1, 0x3, |out| {
+            put_u64(out, 0);
+            put_u64(out, 0);
+            put_u32(out, id);
+            put_u32(out, 0);
+            put_u64(out, track.movie_duration());
+            out.extend_from_slice(&[0; 8]);
+            put_u16(out, 0);
+            put_u16(out, 0);
+            put_u16(out, if video { 0 } else { 0x0100 });
+            put_u16(out, 0);
+            put_matrix(out);
+            put_u32(out, if video { track.width } else { 0 });
+            put_u32(out, if video { track.height } else { 0 });
+        });
+
+        if !track.edits.is_empty() {
+            write_box(out, b"edts", |out| {
+                write_full_box(out, b"elst", 1, 0, |out| {
+                    put_u32(out, track.edits.len() as u32);
+                    for edit in &track.edits {
+                        put_u64(
+                            out,
+                            rescale(
+                                edit.segment_duration,
+                                track.movie_timescale,
+                                MOVIE_TIMESCALE,
+                            ),
+                        );
+                        put_u64(out, edit.media_time as u64);
+                        put_u32(out, 0x0001_0000);
+                    }
+                });
+            });
+        }
+
+        write_box(out, b"mdia", |out| {
+            write_full_box(out, b"mdhd", 1, 0, |out| {
+                put_u64(out, 0);
+                put_u64(out, 0);
+                put_u32(out, track.timescale);
+                put_u64(out, track.media_duration());
+                put_u16(out, track.language);
+                put_u16(out, 0);
+            });
+
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                put_u32(out, 0);
+                out.extend_from_slice(&track.handler);
+                out.extend_from_slice(&[0; 12]);
+                out.extend_from_slice(if video {
+                    b"VideoHandler\0"
+                } else {
+                    b"SoundHandler\0"
+                });
+            });
+
+            write_box(out, b"minf", |out| {
+                if video {
+                    write_full_box(out, b"vmhd", 0, 0x1, |out| out.extend_from_slice(&[0; 8]));
+                } else {
+                    write_full_box(out, b"smhd", 0, 0, |out| out.extend_from_slice(&[0; 4]));
+                }
+
+                write_box(out, b"dinf", |out| {
+                    write_full_box(out, b"dref", 0, 0, |out| {
+                        put_u32(out, 1);
+                        // media data is in the same file
+                        write_full_box(out, b"url ", 0, 0x1, |_| {});
+                    });
+                });
+
+                write_box(out, b"stbl", |out| {
+                    write_sample_table(out, track, chunks, offsets)
+                });
+            });
+        });
+    });
+}
+
+fn write_sample_table(out: &mut Vec<u8>, track: &Track, chunks: &[&Chunk], offsets: &[u64]) {
+    let samples = &track.samples;
+
+    write_box(out, b"stsd", |out| out.extend_from_slice(&track.stsd));
+
+    write_full_box(out, b"stts", 0, 0, |out| {
+        let runs = run_lengths(samples.iter().map(|sample| sample.duration));
+        put_u32(out, runs.len() as u32);
+        for (count, duration) in runs {
+            put_u32(out, count);
+            put_u32(out, duration);
+        }
+    });
+
+    if samples.iter().any(|sample| sample.cts != 0) {
+        let negative = samples.iter().any(|sample| sample.cts < 0);
+        write_full_box(out, b"ctts", u8::from(negative), 0, |out| {
+            let runs = run_lengths(samples.iter().map(|sample| sample.cts));
+            put_u32(out, runs.len() as u32);
+            for (count, cts) in runs {
+                put_u32(out, count);
+                put_u32(out, cts as u32);
+            }
+        });
+    }
+
+    if samples.iter().any(|sample| !sample.sync) {
+        write_full_box(out, b"stss", 0, 0, |out| {
+            let sync: Vec<u32> = (1..)
+                .zip(samples)
+                .filter(|(_, sample)| sample.sync)
+                .map(|(number, _)| number)
+                .collect();
+            put_u32(out, sync.len() as u32);
+            for number in sync {
+                put_u32(out, number);
+            }
+        });
+    }
+
+    write_full_box(out, b"stsc", 0, 0, |out| {
+        let mut entries = Vec::new();
+        for (number, chunk) in (1u32..).zip(chunks) {
+            if entries
+                .last()
+                .is_none_or(|(_, count)| *count != chunk.count)
+            {
+                entries.push((number, chunk.count));
+            }
+        }
+
+        put_u32(out, entries.len() as u32);
+        for (first_chunk, count) in entries {
+            put_u32(out, first_chunk);
+            put_u32(out, count as u32);
+            put_u32(out, 1);
+        }
+    });
+
+    write_full_box(out, b"stsz", 0, 0, |out| {
+        put_u32(out, 0);
+        put_u32(out, samples.len() as u32);
+        for sample in samples {
+            put_u32(out, sample.size);
+        }
+    });
+
+    write_full_box(out, b"co64", 0, 0, |out| {
+        put_u32(out, chunks.len() as u32);
+        for index in 0..chunks.len() {
+            put_u64(out, offsets.get(index).copied().unwrap_or(0));
+        }
+    });
+}
+
+fn run_lengths<T: PartialEq + Copy>(values: impl Iterator<Item = T>) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == value => *count += 1,
+            _ => runs.push((1, value)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sample_entry(codec: &[u8; 4]) -> Vec<u8> {
+        let mut stsd = Vec::new();
+        put_u32(&mut stsd, 0);
+        put_u32(&mut stsd, 1);
+        write_box(&mut stsd, codec, |out| out.extend_from_slice(&[0; 8]));
+        stsd
+    }
+
+    fn track(handler: &[u8; 4], codec: &[u8; 4], samples: Vec<Sample>) -> Track {
+        Track {
+            id: 1,
+            handler: *handler,
+            codec: *codec,
+            stsd: sample_entry(codec),
+            timescale: 1000,
+            movie_timescale: 1000,
+            language: 0x55c4,
+            width: 640 << 16,
+            height: 360 << 16,
+            edits: Vec::new(),
+            samples,
+        }
+    }
+
+    /// Writes a plain MP4 with one track whose samples are the bytes `1..=count`.
+    fn movie(handler: &[u8; 4], codec: &[u8; 4], count: u8) -> Vec<u8> {
+        let samples = (0..count)
+            .map(|index| Sample {
+                offset: u64::from(index),
+                size: 1,
+                duration: 400,
+                cts: 0,
+                sync: index == 0,
+            })
+            .collect();
+        let data: Vec<u8> = (1..=count).collect();
+
+        let mut output = Vec::new();
+        write_movie(
+            &[(0, track(handler, codec, samples))],
+            &mut [Cursor::new(data)],
+            &mut output,
+        )
+        .unwrap();
+        output
+    }
+
+    fn tracks_of(file: &[u8]) -> Vec<Track> {
+        let boxes = children(file).unwrap();
+        let moov = boxes.iter().find(|(kind, _)| kind == b"moov").unwrap().1;
+        parse_movie(moov, &[], file.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn test_write_and_read_back() {
+        let file = movie(b"vide", b"avc1", 5);
+        let tracks = tracks_of(&file);
+
+        assert_eq!(tracks.len(), 1);
+        let track = &tracks[0];
+        assert_eq!(&track.codec, b"avc1");
+        assert_eq!(track.samples.len(), 5);
+        assert_eq!(track.media_duration(), 2000);
+        assert!(track.samples[0].sync && !track.samples[1].sync);
+
+        let data: Vec<u8> = track
+            .samples
+            .iter()
+            .map(|sample| file[sample.offset as usize])
+            .collect();
+        assert_eq!(data, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_merge_interleaves_tracks() {
+        let video = movie(b"vide", b"avc1", 4);
+        let audio = movie(b"soun", b"mp4a", 3);
+
+        let tracks = vec![
+            (0, tracks_of(&video).remove(0)),
+            (1, tracks_of(&audio).remove(0)),
+        ];
+        let mut output = Vec::new();
+        write_movie(
+            &tracks,
+            &mut [Cursor::new(video), Cursor::new(audio)],
+            &mut output,
+        )
+        .unwrap();
+
+        let merged = tracks_of(&output);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(&merged[0].handler, b"vide");
+        assert_eq!(&merged[1].handler, b"soun");
+
+        let bytes = |track: &Track| -> Vec<u8> {
+            track
+                .samples
+                .iter()
+                .map(|sample| output[sample.offset as usize])
+                .collect()
+        };
+        assert_eq!(bytes(&merged[0]), [1, 2, 3, 4]);
+        assert_eq!(bytes(&merged[1]), [1, 2, 3]);
+        // one chunk of video, then audio covering the same time
+        assert!(merged[1].samples[0].offset < merged[0].samples[3].offset);
+    }
+
+    #[test]
+    fn test_fragmented_input() {
+        let mut moof = Vec::new();
+        write_full_box(&mut moof, b"mfhd", 0, 0, |out| put_u32(out, 1));
+        write_box(&mut moof, b"traf", |out| {
+            // default-base-is-moof, default duration
+            write_full_box(out, b"tfhd", 0, 0x02_0008, |out| {
+                put_u32(out, 1);
+                put_u32(out, 1024);
+            });
+            // data offset, sample sizes
+            write_full_box(out, b"trun", 0, 0x201, |out| {
+                put_u32(out, 2);
+                put_u32(out, 100);
+                put_u32(out, 10);
+                put_u32(out, 20);
+            });
+        });
+
+        let mut tracks = vec![track(b"soun", b"mp4a", Vec::new())];
+        parse_fragment(children(&moof).unwrap()[1].1, 5000, &[], &mut tracks).unwrap();
+
+        let samples = &tracks[0].samples;
+        assert_eq!(samples.len(), 2);
+        assert_eq!((samples[0].offset, samples[0].size), (5100, 10));
+        assert_eq!((samples[1].offset, samples[1].size), (5110, 20));
+        assert_eq!(samples[1].duration, 1024);
+    }
+
+    #[test]
+    fn test_unsupported_codec() {
+        let track = track(b"vide", b"vp09", Vec::new());
+        let err = check_codec(&track).unwrap_err();
+
+        assert!(matches!(err, ProcessingError::Unsupported(_)));
+        assert!(err.to_string().contains("vp09"));
+    }
+
+    #[test]
+    fn test_oversized_sample_table() {
+        let stbl = |fixed_size: u32, count: u32, run: u32| {
+            let mut stbl = Vec::new();
+            write_full_box(&mut stbl, b"stsz", 0, 0, |out| {
+                put_u32(out, fixed_size);
+                put_u32(out, count);
+            });
+            write_full_box(&mut stbl, b"stts", 0, 0, |out| {
+                put_u32(out, 1);
+                put_u32(out, run);
+                put_u32(out, 400);
+            });
+            write_full_box(&mut stbl, b"stco", 0, 0, |out| {
+                put_u32(out, 1);
+                put_u32(out, 0);
+            });
+            write_full_box(&mut stbl, b"stsc", 0, 0, |out| {
+                put_u32(out, 1);
+                put_u32(out, 1);
+                put_u32(out, count);
+                put_u32(out, 1);
+            });
+            stbl
+        };
+
+        for (fixed_size, count) in [(1, u32::MAX), (0, u32::MAX)] {
+            let err = parse_sample_table(&stbl(fixed_size, count, 1), 1024).unwrap_err();
+            assert!(matches!(err, ProcessingError::InvalidInput(_)));
+        }
+
+        let samples = parse_sample_table(&stbl(1, 3, u32::MAX), 1024).unwrap();
+        assert_eq!(samples.len(), 3);
+        assert!(samples.iter().all(|sample| sample.duration == 400));
+    }
+}
@@ -0,0 +1,254 @@
+use std::time::Duration;
+
+use crate::model::error::CobaltError;
+use crate::util::stream::StreamError;
+
+/// Controls how failed requests are retried.
+///
+/// Only transient failures are retried: connection errors, timeouts, 5xx responses and
+/// the cobalt error codes that signal a busy or rate limited instance. Permanent errors
+/// such as `error.api.link.invalid` are returned right away.
+///
+/// The delay before attempt `n + 1` is `base_delay * 2^(n - 1)`, capped at `max_delay`,
+/// with up to `jitter` of it randomly taken off. When the instance asks to wait longer
+/// through `Retry-After` or `RateLimit-Reset`, that wait is honored as long as it does not
+/// exceed `max_delay`; otherwise the error is returned right away.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    respect_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            respect_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a new `RetryPolicy` with 3 attempts, a base delay of 500ms and a max delay of 30s.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a `RetryPolicy` that never retries.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Sets the maximum number of attempts, including the first one.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Sets the delay before the first retry.
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    /// Sets the upper bound for the delay between two attempts.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Sets the fraction of the delay that may be randomly taken off, between `0.0` and `1.0`.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets whether to wait as long as the instance asks through its rate limit headers.
+    ///
+    /// Enabled by default.
+    pub fn respect_retry_after(mut self, value: bool) -> Self {
+        self.respect_retry_after = value;
+        self
+    }
+
+    /// Returns the delay to wait after the given failed attempt, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+
+        delay.mul_f64(1.0 - self.jitter * fastrand::f64())
+    }
+
+    /// Returns whether the given error is transient and worth retrying.
+    ///
+    /// See [`CobaltErrorKind::is_retryable`](crate::model::error::CobaltErrorKind::is_retryable).
+    pub fn is_retryable(error: &CobaltError) -> bool {
+        error.kind().is_retryable()
+    }
+
+    /// Returns whether the given stream error is transient and worth retrying.
+    pub fn is_retryable_stream(error: &StreamError) -> bool {
+        match error {
+            StreamError::Http(err) => {
+                err.is_connect()
+                    || err.is_timeout()
+                    || err.is_body()
+                    || err.status().is_some_and(|status| status.is_server_error())
+            }
+            StreamError::Io(_) | StreamError::TooLarge { .. } => false,
+        }
+    }
+
+    /// Returns the wait requested by the instance for a rate limited error.
+    ///
+    /// `Retry-After` is always honored, the `RateLimit-Reset` window only for
+    /// `error.api.rate_exceeded` or when no requests are left.
+    pub fn retry_after(error: &CobaltError) -> Option<Duration> {
+        error.requested_wait()
+    }
+
+    /// Runs `op` until `should_retry` rejects its result or the attempts run out.
+    ///
+    /// `should_retry` returns `None` to stop, or the minimum time to wait before
+    /// the next attempt, as requested by the server.
+    pub(crate) async fn run<T, E>(
+        &self,
+        mut op: impl AsyncFnMut() -> Result<T, E>,
+        should_retry: impl Fn(&Result<T, E>) -> Option<Duration>,
+    ) -> Result<T, E> {
+        let mut attempt = 1;
+
+        loop {
+            let result = op().await;
+
+            if attempt >= self.max_attempts {
+                return result;
+            }
+
+            let Some(requested) = should_retry(&result) else {
+                return result;
+            };
+
+            let mut delay = self.delay(attempt);
+            if self.respect_retry_after && requested > delay {
+                if requested > self.max_delay {
+                    return result;
+                }
+                delay = requested;
+            }
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::error::RateLimit;
+    use reqwest::header::HeaderMap;
+
+    #[test]
+    fn test_delay_backoff() {
+        let policy = RetryPolicy::new()
+            .base_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_millis(350))
+            .jitter(0.0);
+
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(350));
+    }
+
+    #[test]
+    fn test_retryable_codes() {
+        let error = |code: &str| CobaltError::new(code);
+
+        assert!(RetryPolicy::is_retryable(&error("error.api.capacity")));
+        assert!(RetryPolicy::is_retryable(&error("error.api.fetch.rate")));
+        assert!(!RetryPolicy::is_retryable(&error("error.api.link.invalid")));
+        assert!(!RetryPolicy::is_retryable(&error(
+            "error.api.content.video.private"
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_on_permanent_error() {
+        let policy = RetryPolicy::new().base_delay(Duration::ZERO);
+        let mut calls = 0;
+
+        let result: Result<(), u32> = policy
+            .run(
+                async || {
+                    calls += 1;
+                    Err(calls)
+                },
+                |result| {
+                    result
+                        .as_ref()
+                        .is_err_and(|err| *err < 2)
+                        .then_some(Duration::ZERO)
+                },
+            )
+            .await;
+
+        assert_eq!(result, Err(2));
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_gives_up_on_long_retry_after() {
+        let policy = RetryPolicy::new().max_delay(Duration::from_secs(1));
+        let mut calls = 0;
+
+        let result: Result<(), ()> = policy
+            .run(
+                async || {
+                    calls += 1;
+                    Err(())
+                },
+                |_| Some(Duration::from_secs(60)),
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_retries_capacity_despite_window_reset() {
+        let policy = RetryPolicy::new().base_delay(Duration::ZERO);
+        let mut headers = HeaderMap::new();
+        headers.insert("ratelimit-remaining", "4".parse().unwrap());
+        headers.insert("ratelimit-reset", "600".parse().unwrap());
+        let mut calls = 0;
+
+        let result: Result<(), CobaltError> = policy
+            .run(
+                async || {
+                    calls += 1;
+                    Err(CobaltError::new("error.api.capacity")
+                        .with_rate_limit(RateLimit::from_headers(&headers).unwrap()))
+                },
+                |result| {
+                    let error = result.as_ref().err()?;
+                    RetryPolicy::is_retryable(error)
+                        .then(|| RetryPolicy::retry_after(error).unwrap_or_default())
+                },
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+}
@@ -0,0 +1,242 @@
+//! Reading and writing of ISO-BMFF boxes, shared by the MP4 muxer, tagging, file type
+//! detection and probing.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// Error returned when a box is truncated or missing.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Malformed(pub String);
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Malformed {}
+
+impl From<Malformed> for io::Error {
+    fn from(err: Malformed) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+fn malformed(reason: &str) -> Malformed {
+    Malformed(reason.to_string())
+}
+
+/// Cursor over the payload of a box.
+pub(crate) struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Malformed> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| malformed("truncated box"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Number of bytes left after the cursor.
+    #[cfg_attr(not(feature = "mp4"), allow(dead_code))]
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub(crate) fn skip(&mut self, len: usize) -> Result<(), Malformed> {
+        self.take(len).map(|_| ())
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, Malformed> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Malformed> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Malformed> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn fourcc(&mut self) -> Result<[u8; 4], Malformed> {
+        Ok(self.take(4)?.try_into().unwrap())
+    }
+
+    /// Reads the version and flags of a full box.
+    pub(crate) fn full_box(&mut self) -> Result<(u8, u32), Malformed> {
+        let value = self.u32()?;
+        Ok(((value >> 24) as u8, value & 0x00ff_ffff))
+    }
+}
+
+/// A box type and its payload.
+pub(crate) type Child<'a> = ([u8; 4], &'a [u8]);
+
+/// A box type and the range of its payload in the parent.
+pub(crate) type ChildRange = ([u8; 4], Range<usize>);
+
+/// Returns the types and payload ranges of the child boxes in a box payload.
+pub(crate) fn child_ranges(data: &[u8]) -> Result<Vec<ChildRange>, Malformed> {
+    let mut ranges = Vec::new();
+    let mut bytes = Bytes::new(data);
+
+    while bytes.pos + 8 <= data.len() {
+        let start = bytes.pos;
+        let size = bytes.u32()? as u64;
+        let kind = bytes.fourcc()?;
+
+        let size = match size {
+            0 => (data.len() - start) as u64,
+            1 => bytes.u64()?,
+            size => size,
+        };
+        let header = (bytes.pos - start) as u64;
+        if size < header {
+            return Err(malformed("box size smaller than its header"));
+        }
+
+        let payload_start = bytes.pos;
+        let len = usize::try_from(size - header).map_err(|_| malformed("truncated box"))?;
+        bytes.skip(len)?;
+        ranges.push((kind, payload_start..bytes.pos));
+    }
+
+    Ok(ranges)
+}
+
+/// Splits a box payload into its child boxes.
+pub(crate) fn children(data: &[u8]) -> Result<Vec<Child<'_>>, Malformed> {
+    Ok(child_ranges(data)?
+        .into_iter()
+        .map(|(kind, range)| (kind, &data[range]))
+        .collect())
+}
+
+/// Returns the payload of the first child box of the given type.
+pub(crate) fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>, Malformed> {
+    Ok(children(data)?
+        .into_iter()
+        .find(|(found, _)| found == kind)
+        .map(|(_, payload)| payload))
+}
+
+/// Follows a path of box types, failing if any of them is missing.
+pub(crate) fn require<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Result<&'a [u8], Malformed> {
+    let mut current = data;
+    for kind in path {
+        current = child(current, kind)?.ok_or_else(|| {
+            Malformed(format!("missing `{}` box", String::from_utf8_lossy(*kind)))
+        })?;
+    }
+    Ok(current)
+}
+
+/// Position of a top-level box in a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BoxHeader {
+    pub kind: [u8; 4],
+    pub offset: u64,
+    pub header_len: u64,
+    /// Size of the whole box, including the header.
+    pub size: u64,
+}
+
+impl BoxHeader {
+    /// Reads the payload of the box.
+    pub(crate) fn read_payload<R: Read + Seek>(&self, reader: &mut R) -> io::Result<Vec<u8>> {
+        reader.seek(SeekFrom::Start(self.offset + self.header_len))?;
+        let mut payload = vec![0; (self.size - self.header_len) as usize];
+        reader.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+}
+
+/// Lists the top-level boxes of a file of the given length, without reading their payloads.
+pub(crate) fn top_level<R: Read + Seek>(reader: &mut R, length: u64) -> io::Result<Vec<BoxHeader>> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+
+    while length - offset >= 8 {
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let kind: [u8; 4] = header[4..].try_into().unwrap();
+
+        let (size, header_len) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (length - offset, 8),
+            1 => {
+                let mut large = [0; 8];
+                reader.read_exact(&mut large)?;
+                (u64::from_be_bytes(large), 16)
+            }
+            size => (u64::from(size), 8),
+        };
+
+        if size < header_len || offset.checked_add(size).is_none_or(|end| end > length) {
+            return Err(malformed("box extends past the end of the file").into());
+        }
+
+        boxes.push(BoxHeader {
+            kind,
+            offset,
+            header_len,
+            size,
+        });
+        offset += size;
+    }
+
+    Ok(boxes)
+}
+
+/// Appends a box, filling in its size once `body` has written the payload.
+pub(crate) fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Appends a full box with the given version and flags.
+pub(crate) fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&((u32::from(version) << 24) | flags).to_be_bytes());
+        body(out);
+    });
+}
+
+pub(crate) fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+// only the muxer writes 64-bit fields
+#[cfg_attr(not(feature = "mp4"), allow(dead_code))]
+pub(crate) fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
@@ -1,15 +1,29 @@
 use std::cmp::min;
 use std::ops::Range;
 
-#[derive(Debug, PartialEq)]
+use crate::util::bmff::Bytes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Type {
     GIF,
     JPEG,
     PNG,
     WEBP,
+    HEIC,
+    AVIF,
     MP4,
+    MOV,
+    THREEGP,
     WEBM,
+    MKV,
     MP3,
+    M4A,
+    AAC,
+    /// OGG with Vorbis or another non-Opus codec.
+    OGG,
+    OPUS,
+    WAV,
+    FLAC,
     ZIP,
 }
 
@@ -21,9 +35,20 @@ impl Type {
             Type::JPEG => "jpeg",
             Type::PNG => "png",
             Type::WEBP => "webp",
+            Type::HEIC => "heic",
+            Type::AVIF => "avif",
             Type::MP4 => "mp4",
+            Type::MOV => "mov",
+            Type::THREEGP => "3gp",
             Type::WEBM => "webm",
+            Type::MKV => "mkv",
             Type::MP3 => "mp3",
+            Type::M4A => "m4a",
+            Type::AAC => "aac",
+            Type::OGG => "ogg",
+            Type::OPUS => "opus",
+            Type::WAV => "wav",
+            Type::FLAC => "flac",
             Type::ZIP => "zip",
         }
     }
@@ -35,22 +60,99 @@ impl Type {
             Type::JPEG => "image/jpeg",
             Type::PNG => "image/png",
             Type::WEBP => "image/webp",
+            Type::HEIC => "image/heic",
+            Type::AVIF => "image/avif",
             Type::MP4 => "video/mp4",
+            Type::MOV => "video/quicktime",
+            Type::THREEGP => "video/3gpp",
             Type::WEBM => "video/webm",
+            Type::MKV => "video/x-matroska",
             Type::MP3 => "audio/mpeg",
+            Type::M4A => "audio/mp4",
+            Type::AAC => "audio/aac",
+            Type::OGG => "audio/ogg",
+            Type::OPUS => "audio/opus",
+            Type::WAV => "audio/wav",
+            Type::FLAC => "audio/flac",
             Type::ZIP => "application/x-zip",
         }
     }
 
+    /// Returns the type for a MIME type such as a `Content-Type` value, parameters included.
+    #[must_use]
+    pub fn from_mime(mime: &str) -> Option<Type> {
+        let mut parts = mime.split(';');
+        let essence = parts.next()?.trim().to_ascii_lowercase();
+        let opus = parts.any(|param| {
+            param
+                .split_once('=')
+                .is_some_and(|(key, value)| key.trim() == "codecs" && value.contains("opus"))
+        });
+
+        Some(match essence.as_str() {
+            "audio/ogg" | "audio/opus" if opus => Type::OPUS,
+            "image/jpg" | "image/pjpeg" => Type::JPEG,
+            "image/heif" => Type::HEIC,
+            "audio/mp3" => Type::MP3,
+            "audio/x-m4a" | "audio/m4a" => Type::M4A,
+            "audio/aacp" | "audio/x-aac" => Type::AAC,
+            "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Type::WAV,
+            "audio/x-flac" => Type::FLAC,
+            "audio/webm" => Type::WEBM,
+            "audio/x-matroska" => Type::MKV,
+            "application/ogg" | "video/ogg" => Type::OGG,
+            "video/3gpp2" | "audio/3gpp" => Type::THREEGP,
+            "application/zip" | "application/x-zip-compressed" => Type::ZIP,
+            essence => ALL.into_iter().find(|t| t.as_mime() == essence)?,
+        })
+    }
+
     #[must_use]
     pub fn is_video(&self) -> bool {
-        matches!(self, Type::MP4 | Type::WEBM)
+        matches!(
+            self,
+            Type::MP4 | Type::MOV | Type::THREEGP | Type::WEBM | Type::MKV
+        )
+    }
+
+    #[must_use]
+    pub fn is_audio(&self) -> bool {
+        matches!(
+            self,
+            Type::MP3 | Type::M4A | Type::AAC | Type::OGG | Type::OPUS | Type::WAV | Type::FLAC
+        )
     }
 }
 
+const ALL: [Type; 19] = [
+    Type::GIF,
+    Type::JPEG,
+    Type::PNG,
+    Type::WEBP,
+    Type::HEIC,
+    Type::AVIF,
+    Type::MP4,
+    Type::MOV,
+    Type::THREEGP,
+    Type::WEBM,
+    Type::MKV,
+    Type::MP3,
+    Type::M4A,
+    Type::AAC,
+    Type::OGG,
+    Type::OPUS,
+    Type::WAV,
+    Type::FLAC,
+    Type::ZIP,
+];
+
 const WEBP: [u8; 4] = [87, 69, 66, 80];
+const WAVE: [u8; 4] = *b"WAVE";
 const MP4: [u8; 4] = [0x66, 0x74, 0x79, 0x70];
 
+/// EBML element ID of the Matroska `DocType`.
+const DOC_TYPE: u32 = 0x4282;
+
 fn bounded_range(start: usize, end: usize, len: usize) -> Range<usize> {
     min(len, start)..min(len, end)
 }
@@ -64,22 +166,322 @@ fn check_webp(that: &[u8]) -> bool {
     sig(bytes_offset_removed, &WEBP)
 }
 
+fn check_wav(that: &[u8]) -> bool {
+    that.len() >= 12 && sig(&that[8..12], &WAVE)
+}
+
 fn check_mp4(that: &[u8]) -> bool {
     let bytes_offset_removed = &that[bounded_range(4, 8, that.len())];
     sig(bytes_offset_removed, &MP4)
 }
 
+/// Brands of an ISO-BMFF `ftyp` box, which tell MP4, M4A, MOV, 3GP, HEIC and AVIF apart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ftyp {
+    pub major_brand: [u8; 4],
+    pub minor_version: u32,
+    /// Compatible brands, cut short if the box is longer than the bytes it was parsed from.
+    pub compatible_brands: Vec<[u8; 4]>,
+}
+
+impl Ftyp {
+    /// Parses the `ftyp` box at the start of a file.
+    #[must_use]
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 16 || !check_mp4(buf) {
+            return None;
+        }
+
+        let size = u32::from_be_bytes(buf.get(..4)?.try_into().unwrap()) as usize;
+        let payload = buf.get(8..size.clamp(8, buf.len()))?;
+
+        let mut bytes = Bytes::new(payload);
+        let major_brand = bytes.fourcc().ok()?;
+        let minor_version = bytes.u32().ok()?;
+        let compatible_brands = std::iter::from_fn(|| bytes.fourcc().ok()).collect();
+
+        Some(Self {
+            major_brand,
+            minor_version,
+            compatible_brands,
+        })
+    }
+
+    /// Returns whether the brand is the major brand or one of the compatible brands.
+    #[must_use]
+    pub fn has_brand(&self, brand: &[u8; 4]) -> bool {
+        self.major_brand == *brand || self.compatible_brands.contains(brand)
+    }
+
+    /// Classifies the file by its major brand, or by its compatible brands when the major
+    /// brand is a generic one such as `isom` or `mif1`.
+    ///
+    /// Audio is only inferred from a compatible `M4A ` brand when the major brand is
+    /// `dash` or `iso*` and no video brand is listed: video files such as iTunes `.m4v`
+    /// list `M4A ` as a compatible brand too, while DASH audio only says so there.
+    #[must_use]
+    pub fn kind(&self) -> Type {
+        brand_kind(&self.major_brand)
+            .or_else(|| {
+                // image brands first, HEIF files list video brands as well
+                let compatible = |kinds: &[Type]| {
+                    self.compatible_brands
+                        .iter()
+                        .filter_map(brand_kind)
+                        .find(|kind| kinds.contains(kind))
+                };
+                let generic = &self.major_brand == b"dash" || self.major_brand.starts_with(b"iso");
+                let audio = generic
+                    && compatible(&[Type::MP4]).is_none()
+                    && compatible(&[Type::M4A]).is_some();
+
+                compatible(&[Type::AVIF, Type::HEIC])
+                    .or_else(|| compatible(&[Type::MOV, Type::THREEGP]))
+                    .or_else(|| audio.then_some(Type::M4A))
+            })
+            .unwrap_or(match &self.major_brand {
+                b"mif1" | b"msf1" => Type::HEIC,
+                _ => Type::MP4,
+            })
+    }
+}
+
+/// Returns the type that a brand stands for, if it is specific to one.
+fn brand_kind(brand: &[u8; 4]) -> Option<Type> {
+    match brand {
+        b"M4A " | b"M4B " | b"M4P " => Some(Type::M4A),
+        b"M4V " | b"M4VH" | b"M4VP" => Some(Type::MP4),
+        b"qt  " => Some(Type::MOV),
+        [b'3', b'g', b'p' | b'2', _] => Some(Type::THREEGP),
+        b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" => Some(Type::HEIC),
+        b"avif" | b"avis" => Some(Type::AVIF),
+        _ => None,
+    }
+}
+
+/// Tells OGG files apart by the codec in the first packet of the first page.
+fn check_ogg(that: &[u8]) -> Type {
+    let segments = that.get(26).copied().unwrap_or(0) as usize;
+    let packet = &that[bounded_range(27 + segments, 27 + segments + 8, that.len())];
+
+    match packet {
+        b"OpusHead" => Type::OPUS,
+        _ => Type::OGG,
+    }
+}
+
+/// Reads an EBML variable-length integer, keeping the length marker if `marker` is set
+/// as element IDs do. Returns the value and its length.
+pub(crate) fn ebml_vint(that: &[u8], marker: bool) -> Option<(u64, usize)> {
+    let first = *that.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || that.len() < len {
+        return None;
+    }
+
+    // an 8-byte vint keeps no value bits in its first byte, which a `u8` cannot shift
+    let first = if marker {
+        first
+    } else {
+        first & (0xff_u16 >> len) as u8
+    };
+    let value = that[1..len].iter().fold(u64::from(first), |value, byte| {
+        (value << 8) | u64::from(*byte)
+    });
+
+    Some((value, len))
+}
+
+/// Tells WebM and other Matroska files apart by the `DocType` of the EBML header.
+///
+/// Files whose `DocType` lies past the sniffed bytes are assumed to be WebM.
+fn check_matroska(that: &[u8]) -> Type {
+    let doc_type = || {
+        let (_, len) = ebml_vint(that.get(4..)?, false)?;
+        let mut rest = that.get(4 + len..)?;
+
+        loop {
+            let (id, id_len) = ebml_vint(rest, true)?;
+            let (size, size_len) = ebml_vint(rest.get(id_len..)?, false)?;
+            let data = rest.get(id_len + size_len..)?;
+
+            if id == u64::from(DOC_TYPE) {
+                return data.get(..size as usize);
+            }
+            rest = data.get(size as usize..)?;
+        }
+    };
+
+    match doc_type() {
+        Some(b"webm") | None => Type::WEBM,
+        Some(_) => Type::MKV,
+    }
+}
+
+/// Checks for an MPEG audio frame header: a frame sync, a valid version, layer, bitrate
+/// and sample rate. Returns the layer, where 3 is layer III.
+pub(crate) fn mpeg_layer(that: &[u8]) -> Option<u8> {
+    let [0xff, second, third, ..] = *that else {
+        return None;
+    };
+
+    let version = (second >> 3) & 0b11;
+    let layer = 4 - ((second >> 1) & 0b11);
+    let bitrate = third >> 4;
+    let sample_rate = (third >> 2) & 0b11;
+
+    (second & 0xe0 == 0xe0 && version != 0b01 && layer != 4 && bitrate != 0xf && sample_rate != 3)
+        .then_some(layer)
+}
+
+/// Checks for an AAC ADTS header: a frame sync with the layer bits set to zero.
+fn check_adts(that: &[u8]) -> bool {
+    matches!(that, [0xff, second, third, ..] if second & 0xf6 == 0xf0 && (third >> 2) & 0xf < 13)
+}
+
 #[must_use]
 pub fn get_sig(buf: &[u8]) -> Option<Type> {
     match buf {
         [71, 73, 70, ..] => Some(Type::GIF),
         [255, 216, 255, ..] => Some(Type::JPEG),
         [137, 80, 78, 71, 13, 10, 26, 10, ..] => Some(Type::PNG),
-        [0x1A, 0x45, 0xDF, 0xA3, ..] => Some(Type::WEBM),
-        [0x49, 0x44, 0x33, ..] /* ID3 tagged */ | [0xff, 0xfb, ..] /* untagged */ => Some(Type::MP3),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => Some(check_matroska(buf)),
+        [0x49, 0x44, 0x33, ..] /* ID3 tagged */ => Some(Type::MP3),
+        [b'O', b'g', b'g', b'S', ..] => Some(check_ogg(buf)),
+        [b'f', b'L', b'a', b'C', ..] => Some(Type::FLAC),
         [0x50, 0x4b, ..] => Some(Type::ZIP),
+        _ if check_adts(buf) => Some(Type::AAC),
+        _ if mpeg_layer(buf) == Some(3) /* untagged */ => Some(Type::MP3),
+        [b'R', b'I', b'F', b'F', ..] if check_wav(buf) => Some(Type::WAV),
         _ if check_webp(buf) => Some(Type::WEBP),
-        _ if check_mp4(buf) => Some(Type::MP4),
-        _ => None,
+        _ => Ftyp::parse(buf).map(|ftyp| ftyp.kind()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the start of an OGG page holding a single packet.
+    fn ogg_page(packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x02".to_vec();
+        page.extend_from_slice(&[0; 20]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+        let mut head = vec![0, 0, 0, 0x18];
+        head.extend_from_slice(b"ftyp");
+        head.extend_from_slice(brand);
+        head.extend_from_slice(&[0; 12]);
+        head
+    }
+
+    #[test]
+    fn test_audio_signatures() {
+        assert_eq!(get_sig(&ogg_page(b"OpusHead\x01\x02")), Some(Type::OPUS));
+        assert_eq!(get_sig(&ogg_page(b"\x01vorbis\x00")), Some(Type::OGG));
+        assert_eq!(get_sig(b"RIFF\x24\x00\x00\x00WAVEfmt "), Some(Type::WAV));
+        assert_eq!(get_sig(b"RIFF\x24\x00\x00\x00WEBPVP8 "), Some(Type::WEBP));
+        assert_eq!(get_sig(b"fLaC\x00\x00\x00\x22"), Some(Type::FLAC));
+        assert_eq!(get_sig(&[0xff, 0xf1, 0x50, 0x80]), Some(Type::AAC));
+        assert_eq!(get_sig(&[0xff, 0xf9, 0x50, 0x80]), Some(Type::AAC));
+
+        // MPEG-1, MPEG-2 and MPEG-2.5 layer III, with and without CRC
+        for second in [0xfb, 0xfa, 0xf3, 0xf2, 0xe3] {
+            assert_eq!(get_sig(&[0xff, second, 0x90, 0x00]), Some(Type::MP3));
+        }
+        // reserved version, layer II and a free bitrate marker that is out of range
+        assert_eq!(get_sig(&[0xff, 0xeb, 0x90, 0x00]), None);
+        assert_eq!(get_sig(&[0xff, 0xfd, 0x90, 0x00]), None);
+        assert_eq!(get_sig(&[0xff, 0xfb, 0xf0, 0x00]), None);
+    }
+
+    #[test]
+    fn test_container_signatures() {
+        assert_eq!(get_sig(&ftyp(b"isom")), Some(Type::MP4));
+        assert_eq!(get_sig(&ftyp(b"M4A ")), Some(Type::M4A));
+        assert_eq!(get_sig(&ftyp(b"qt  ")), Some(Type::MOV));
+        assert_eq!(get_sig(&ftyp(b"3gp5")), Some(Type::THREEGP));
+        assert_eq!(get_sig(&ftyp(b"heic")), Some(Type::HEIC));
+        assert_eq!(get_sig(&ftyp(b"avif")), Some(Type::AVIF));
+        assert!(!Type::M4A.is_video());
+
+        let ebml = |doc_type: &[u8]| {
+            let mut head = vec![0x1a, 0x45, 0xdf, 0xa3, 0x9f];
+            head.extend_from_slice(&[0x42, 0x86, 0x81, 0x01]);
+            head.extend_from_slice(&[0x42, 0x82, 0x80 | doc_type.len() as u8]);
+            head.extend_from_slice(doc_type);
+            head
+        };
+        assert_eq!(get_sig(&ebml(b"webm")), Some(Type::WEBM));
+        assert_eq!(get_sig(&ebml(b"matroska")), Some(Type::MKV));
+
+        // mkvmerge writes the header size as an 8-byte vint
+        let mut head = ebml(b"matroska");
+        head.splice(4..5, [0x01, 0, 0, 0, 0, 0, 0, 0x1f]);
+        assert_eq!(get_sig(&head), Some(Type::MKV));
+        assert_eq!(get_sig(&[0x1a, 0x45, 0xdf, 0xa3]), Some(Type::WEBM));
+    }
+
+    #[test]
+    fn test_from_mime() {
+        assert_eq!(Type::from_mime("video/mp4"), Some(Type::MP4));
+        assert_eq!(Type::from_mime("Audio/MPEG"), Some(Type::MP3));
+        assert_eq!(Type::from_mime("audio/ogg; codecs=opus"), Some(Type::OPUS));
+        assert_eq!(Type::from_mime("audio/ogg"), Some(Type::OGG));
+        assert_eq!(Type::from_mime("audio/x-wav"), Some(Type::WAV));
+        assert_eq!(Type::from_mime("application/octet-stream"), None);
+    }
+
+    #[test]
+    fn test_ftyp_brands() {
+        let ftyp = |major: &[u8; 4], compatible: &[&[u8; 4]]| {
+            let mut head = ((16 + 4 * compatible.len()) as u32).to_be_bytes().to_vec();
+            head.extend_from_slice(b"ftyp");
+            head.extend_from_slice(major);
+            head.extend_from_slice(&0x200u32.to_be_bytes());
+            compatible
+                .iter()
+                .for_each(|brand| head.extend_from_slice(*brand));
+            // the start of the next box must not be read as a brand
+            head.extend_from_slice(b"\0\0\0\x08free");
+            head
+        };
+
+        let parsed = Ftyp::parse(&ftyp(b"M4A ", &[b"M4A ", b"mp42", b"isom"])).unwrap();
+        assert_eq!(parsed.major_brand, *b"M4A ");
+        assert_eq!(parsed.minor_version, 0x200);
+        assert_eq!(parsed.compatible_brands, [*b"M4A ", *b"mp42", *b"isom"]);
+        assert!(parsed.has_brand(b"mp42"));
+        assert_eq!(parsed.kind(), Type::M4A);
+
+        let kind = |major, compatible| Ftyp::parse(&ftyp(major, compatible)).unwrap().kind();
+        assert_eq!(
+            kind(b"isom", &[b"isom", b"iso2", b"avc1", b"mp41"]),
+            Type::MP4
+        );
+        assert_eq!(kind(b"mif1", &[b"mif1", b"miaf", b"avif"]), Type::AVIF);
+        assert_eq!(kind(b"mif1", &[b"mif1", b"heic"]), Type::HEIC);
+        assert_eq!(kind(b"mif1", &[b"mif1"]), Type::HEIC);
+        assert_eq!(kind(b"mp42", &[b"mp42", b"M4A "]), Type::MP4);
+        assert_eq!(
+            kind(b"M4V ", &[b"M4V ", b"M4A ", b"mp42", b"isom"]),
+            Type::MP4
+        );
+        assert_eq!(
+            kind(b"mp42", &[b"M4V ", b"M4A ", b"mp42", b"isom"]),
+            Type::MP4
+        );
+        assert_eq!(kind(b"isom", &[b"isom", b"M4A ", b"M4V "]), Type::MP4);
+        // DASH audio as served by YouTube
+        assert_eq!(kind(b"dash", &[b"iso6", b"M4A ", b"mp42"]), Type::M4A);
+        assert_eq!(kind(b"dash", &[b"iso6", b"avc1", b"mp41"]), Type::MP4);
+
+        assert_eq!(Ftyp::parse(b"\0\0\0\x08moov"), None);
+        assert_eq!(Ftyp::parse(b"\0\0\0\x08ft"), None);
     }
 }
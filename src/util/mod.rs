@@ -1,3 +1,8 @@
+pub(crate) mod bmff;
 pub mod filetype;
+pub mod probe;
+pub mod progress;
+pub mod service;
 pub mod stream;
+pub mod tags;
 pub mod write;
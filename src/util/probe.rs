@@ -0,0 +1,793 @@
+//! Probing of downloaded media for the duration, resolution, codecs and bitrate, without
+//! running ffprobe.
+//!
+//! MP4 and the other ISO-BMFF formats, WebM and Matroska, MP3 and OGG are supported. Other
+//! files only get their type detected.
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::util::bmff::{self, Bytes};
+use crate::util::filetype::{self, Type};
+
+/// Number of bytes read from the end of an OGG file to find the last granule position.
+const OGG_TAIL_LEN: u64 = 64 * 1024;
+
+/// Largest Matroska `Info` or `Tracks` element that is read.
+const MAX_ELEMENT_LEN: u64 = 1024 * 1024;
+
+/// Properties of a media file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaInfo {
+    /// File type detected from the first bytes.
+    pub file_type: Option<Type>,
+    pub duration: Option<Duration>,
+    /// Width of the first video track, in pixels.
+    pub width: Option<u32>,
+    /// Height of the first video track, in pixels.
+    pub height: Option<u32>,
+    /// Codec of the first video track as named by the container, e.g. `avc1` or `V_VP9`.
+    pub video_codec: Option<String>,
+    /// Codec of the first audio track as named by the container, e.g. `mp4a` or `A_OPUS`.
+    pub audio_codec: Option<String>,
+    /// Average bitrate in bits per second.
+    pub bitrate: Option<u64>,
+}
+
+/// Probes a file held in memory.
+pub fn probe(bytes: &[u8]) -> io::Result<MediaInfo> {
+    probe_reader(&mut Cursor::new(bytes), bytes.len() as u64)
+}
+
+/// Probes a file on disk, reading only the parts that hold the properties.
+///
+/// This does blocking I/O, use `tokio::task::spawn_blocking` from async code.
+pub fn probe_file(path: impl AsRef<Path>) -> io::Result<MediaInfo> {
+    let file = File::open(path)?;
+    let length = file.metadata()?.len();
+
+    probe_reader(&mut BufReader::new(file), length)
+}
+
+fn probe_reader<R: Read + Seek>(reader: &mut R, length: u64) -> io::Result<MediaInfo> {
+    let head = read_at(reader, 0, 64)?;
+    let file_type = filetype::get_sig(&head);
+
+    let mut info = match file_type {
+        Some(Type::MP4 | Type::MOV | Type::THREEGP | Type::M4A) => probe_mp4(reader, length)?,
+        Some(Type::WEBM | Type::MKV) => probe_matroska(reader, length)?,
+        Some(Type::MP3) => probe_mp3(reader, length)?,
+        Some(Type::OGG | Type::OPUS) => probe_ogg(reader, length)?,
+        _ => MediaInfo::default(),
+    };
+    info.file_type = file_type;
+
+    if info.bitrate.is_none()
+        && let Some(duration) = info.duration.filter(|duration| !duration.is_zero())
+    {
+        info.bitrate = Some((length as f64 * 8.0 / duration.as_secs_f64()) as u64);
+    }
+
+    Ok(info)
+}
+
+/// Reads up to `len` bytes at `offset`, fewer if the file ends before.
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn fourcc_name(fourcc: &[u8; 4]) -> String {
+    String::from_utf8_lossy(fourcc).trim().to_string()
+}
+
+fn probe_mp4<R: Read + Seek>(reader: &mut R, length: u64) -> io::Result<MediaInfo> {
+    let moov = bmff::top_level(reader, length)?
+        .into_iter()
+        .find(|header| &header.kind == b"moov")
+        .ok_or_else(|| invalid("missing `moov` box"))?
+        .read_payload(reader)?;
+
+    let mut info = MediaInfo {
+        duration: mp4_duration(&moov)?,
+        ..MediaInfo::default()
+    };
+
+    for (kind, trak) in bmff::children(&moov)? {
+        if &kind != b"trak" {
+            continue;
+        }
+
+        let mut hdlr = Bytes::new(bmff::require(trak, &[b"mdia", b"hdlr"])?);
+        hdlr.full_box()?;
+        hdlr.skip(4)?;
+        let handler = hdlr.fourcc()?;
+
+        let stsd = bmff::require(trak, &[b"mdia", b"minf", b"stbl", b"stsd"])?;
+        let Some((codec, entry)) = bmff::children(stsd.get(8..).unwrap_or_default())?
+            .into_iter()
+            .next()
+        else {
+            continue;
+        };
+
+        match &handler {
+            b"vide" if info.video_codec.is_none() => {
+                info.video_codec = Some(fourcc_name(&codec));
+
+                let (mut width, mut height) = match bmff::child(trak, b"tkhd")? {
+                    Some(tkhd) => tkhd_size(tkhd)?,
+                    None => (0, 0),
+                };
+                if width == 0 || height == 0 {
+                    // visual sample entries store the coded size after 24 bytes of fields
+                    let mut entry = Bytes::new(entry);
+                    entry.skip(24)?;
+                    width = u32::from(entry.u16()?);
+                    height = u32::from(entry.u16()?);
+                }
+
+                info.width = Some(width);
+                info.height = Some(height);
+            }
+            b"soun" if info.audio_codec.is_none() => {
+                info.audio_codec = Some(fourcc_name(&codec));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(info)
+}
+
+/// Reads the movie duration from `mvhd`, or from `mehd` for fragmented files.
+fn mp4_duration(moov: &[u8]) -> io::Result<Option<Duration>> {
+    let mut mvhd = Bytes::new(bmff::require(moov, &[b"mvhd"])?);
+    let (version, _) = mvhd.full_box()?;
+    let (timescale, mut duration) = if version == 1 {
+        mvhd.skip(16)?;
+        (mvhd.u32()?, mvhd.u64()?)
+    } else {
+        mvhd.skip(8)?;
+        (mvhd.u32()?, u64::from(mvhd.u32()?))
+    };
+
+    if (duration == 0 || duration == u64::from(u32::MAX))
+        && let Some(mvex) = bmff::child(moov, b"mvex")?
+        && let Some(mehd) = bmff::child(mvex, b"mehd")?
+    {
+        let mut mehd = Bytes::new(mehd);
+        duration = match mehd.full_box()?.0 {
+            1 => mehd.u64()?,
+            _ => u64::from(mehd.u32()?),
+        };
+    }
+
+    Ok(
+        (timescale != 0 && duration != 0 && duration != u64::from(u32::MAX))
+            .then(|| Duration::try_from_secs_f64(duration as f64 / f64::from(timescale)).ok())
+            .flatten(),
+    )
+}
+
+/// Reads the presentation size from a `tkhd` box, rounding the 16.16 fixed point values.
+fn tkhd_size(tkhd: &[u8]) -> io::Result<(u32, u32)> {
+    let mut tkhd = Bytes::new(tkhd);
+    let (version, _) = tkhd.full_box()?;
+    // times, track ID and duration, then reserved, layer, group, volume and the matrix
+    tkhd.skip(if version == 1 { 32 } else { 20 })?;
+    tkhd.skip(52)?;
+
+    // 16.16 fixed point, rounded to the nearest pixel
+    let round = |value: u32| ((u64::from(value) + 0x8000) >> 16) as u32;
+    let width = tkhd.u32()?;
+    let height = tkhd.u32()?;
+    Ok((round(width), round(height)))
+}
+
+mod ebml {
+    pub const SEGMENT: u64 = 0x1853_8067;
+    pub const INFO: u64 = 0x1549_a966;
+    pub const TRACKS: u64 = 0x1654_ae6b;
+    pub const CLUSTER: u64 = 0x1f43_b675;
+    pub const TIMESTAMP_SCALE: u64 = 0x2a_d7b1;
+    pub const DURATION: u64 = 0x4489;
+    pub const TRACK_ENTRY: u64 = 0xae;
+    pub const TRACK_TYPE: u64 = 0x83;
+    pub const CODEC_ID: u64 = 0x86;
+    pub const VIDEO: u64 = 0xe0;
+    pub const PIXEL_WIDTH: u64 = 0xb0;
+    pub const PIXEL_HEIGHT: u64 = 0xba;
+}
+
+/// An EBML element header: the ID, the size of the data, or `None` if it is unknown,
+/// and the length of the header.
+type ElementHeader = (u64, Option<u64>, usize);
+
+fn element_header(data: &[u8]) -> Option<ElementHeader> {
+    let (id, id_len) = filetype::ebml_vint(data, true)?;
+    let (size, size_len) = filetype::ebml_vint(data.get(id_len..)?, false)?;
+
+    // a size with all bits set means the element runs until its parent ends
+    let unknown = size == (1 << (7 * size_len)) - 1;
+    Some((id, (!unknown).then_some(size), id_len + size_len))
+}
+
+/// Splits the data of a master element into its child elements.
+fn ebml_children(mut data: &[u8]) -> Vec<(u64, &[u8])> {
+    let mut children = Vec::new();
+
+    while let Some((id, size, header_len)) = element_header(data) {
+        let rest = &data[header_len..];
+        let size = size.map_or(rest.len(), |size| (size as usize).min(rest.len()));
+
+        children.push((id, &rest[..size]));
+        data = &rest[size..];
+    }
+
+    children
+}
+
+fn ebml_uint(data: &[u8]) -> u64 {
+    data.iter()
+        .fold(0, |value, byte| (value << 8) | u64::from(*byte))
+}
+
+fn ebml_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f64::from(f32::from_be_bytes(data.try_into().unwrap()))),
+        8 => Some(f64::from_be_bytes(data.try_into().unwrap())),
+        _ => None,
+    }
+}
+
+fn probe_matroska<R: Read + Seek>(reader: &mut R, length: u64) -> io::Result<MediaInfo> {
+    let mut info = MediaInfo::default();
+    let mut offset = 0;
+    let mut end = length;
+
+    while offset < end {
+        let header = read_at(reader, offset, 12)?;
+        let Some((id, size, header_len)) = element_header(&header) else {
+            break;
+        };
+        let data_start = offset + header_len as u64;
+
+        match id {
+            // the segment holds everything else, step inside of it
+            ebml::SEGMENT => {
+                end = size.map_or(length, |size| (data_start + size).min(length));
+                offset = data_start;
+                continue;
+            }
+            ebml::INFO | ebml::TRACKS => {
+                let size = size.ok_or_else(|| invalid("element of unknown size"))?;
+                let data = read_at(reader, data_start, size.min(MAX_ELEMENT_LEN))?;
+
+                if id == ebml::INFO {
+                    info.duration = matroska_duration(&data);
+                } else {
+                    matroska_tracks(&data, &mut info);
+                }
+            }
+            // all the metadata comes before the media data in the files cobalt sends
+            ebml::CLUSTER => break,
+            _ => {}
+        }
+
+        match size {
+            Some(size) => offset = data_start + size,
+            None => break,
+        }
+    }
+
+    Ok(info)
+}
+
+fn matroska_duration(data: &[u8]) -> Option<Duration> {
+    let children = ebml_children(data);
+    let field = |id| {
+        children
+            .iter()
+            .find(|(found, _)| *found == id)
+            .map(|(_, data)| *data)
+    };
+
+    let scale = field(ebml::TIMESTAMP_SCALE).map_or(1_000_000, ebml_uint);
+    let duration = field(ebml::DURATION).and_then(ebml_float)?;
+
+    Duration::try_from_secs_f64(duration * scale as f64 / 1e9).ok()
+}
+
+fn matroska_tracks(data: &[u8], info: &mut MediaInfo) {
+    for (id, entry) in ebml_children(data) {
+        if id != ebml::TRACK_ENTRY {
+            continue;
+        }
+
+        let children = ebml_children(entry);
+        let field = |id| {
+            children
+                .iter()
+                .find(|(found, _)| *found == id)
+                .map(|(_, data)| *data)
+        };
+        let codec = field(ebml::CODEC_ID).map(|codec| {
+            String::from_utf8_lossy(codec)
+                .trim_end_matches('\0')
+                .to_string()
+        });
+
+        match field(ebml::TRACK_TYPE).map(ebml_uint) {
+            Some(1) if info.video_codec.is_none() => {
+                info.video_codec = codec;
+
+                let video = ebml_children(field(ebml::VIDEO).unwrap_or_default());
+                let size = |id| {
+                    video
+                        .iter()
+                        .find(|(found, _)| *found == id)
+                        .map(|(_, data)| ebml_uint(data) as u32)
+                };
+                info.width = size(ebml::PIXEL_WIDTH);
+                info.height = size(ebml::PIXEL_HEIGHT);
+            }
+            Some(2) if info.audio_codec.is_none() => info.audio_codec = codec,
+            _ => {}
+        }
+    }
+}
+
+/// Bitrates of MPEG-1 and MPEG-2 layer III, in kbit/s.
+const MP3_BITRATES: [[u32; 15]; 2] = [
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// Sample rates of MPEG-1, the other versions divide them by 2 and 4.
+const MP3_SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// A layer III frame header.
+struct Mp3Frame {
+    mpeg1: bool,
+    mono: bool,
+    /// Bitrate in bit/s, zero for free format streams.
+    bitrate: u32,
+    sample_rate: u32,
+}
+
+impl Mp3Frame {
+    fn parse(header: &[u8]) -> Option<Self> {
+        let [0xff, second, third, fourth, ..] = *header else {
+            return None;
+        };
+
+        let version = (second >> 3) & 0b11;
+        let bitrate = (third >> 4) as usize;
+        let sample_rate = ((third >> 2) & 0b11) as usize;
+        if second & 0xe0 != 0xe0
+            || version == 0b01
+            || (second >> 1) & 0b11 != 0b01
+            || bitrate == 0xf
+            || sample_rate == 3
+        {
+            return None;
+        }
+
+        let mpeg1 = version == 0b11;
+        let divisor = match version {
+            0b11 => 1,
+            0b10 => 2,
+            _ => 4,
+        };
+
+        Some(Self {
+            mpeg1,
+            mono: fourth >> 6 == 0b11,
+            bitrate: MP3_BITRATES[usize::from(!mpeg1)][bitrate] * 1000,
+            sample_rate: MP3_SAMPLE_RATES[sample_rate] / divisor,
+        })
+    }
+
+    fn samples(&self) -> u32 {
+        if self.mpeg1 { 1152 } else { 576 }
+    }
+
+    /// Offset of a Xing or Info header from the frame start, after the side information.
+    fn xing_offset(&self) -> usize {
+        4 + match (self.mpeg1, self.mono) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        }
+    }
+}
+
+fn probe_mp3<R: Read + Seek>(reader: &mut R, length: u64) -> io::Result<MediaInfo> {
+    let head = read_at(reader, 0, 10)?;
+    let mut start = 0;
+    if head.starts_with(b"ID3") && head.len() == 10 {
+        let size = head[6..10]
+            .iter()
+            .fold(0, |value, byte| (value << 7) | u64::from(byte & 0x7f));
+        let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+        start = 10 + size + footer;
+    }
+
+    // tags may be followed by padding, look for the first frame
+    let data = read_at(reader, start, 16 * 1024)?;
+    let Some((position, frame)) =
+        (0..data.len()).find_map(|position| Some((position, Mp3Frame::parse(&data[position..])?)))
+    else {
+        return Ok(MediaInfo::default());
+    };
+    start += position as u64;
+    let data = &data[position..];
+
+    let mut info = MediaInfo {
+        audio_codec: Some("mp3".to_string()),
+        ..MediaInfo::default()
+    };
+
+    // VBR files describe the whole stream in their first frame
+    let vbr = vbr_header(data, &frame);
+    if let Some((Some(frames), bytes)) = vbr {
+        let duration =
+            f64::from(frames) * f64::from(frame.samples()) / f64::from(frame.sample_rate);
+        info.duration = Duration::try_from_secs_f64(duration).ok();
+
+        if let Some(bytes) = bytes.filter(|_| duration > 0.0) {
+            info.bitrate = Some((f64::from(bytes) * 8.0 / duration) as u64);
+        }
+        return Ok(info);
+    }
+
+    if frame.bitrate == 0 {
+        return Ok(info);
+    }
+
+    let mut end = length;
+    if length >= 128 && read_at(reader, length - 128, 3)? == b"TAG" {
+        end -= 128;
+    }
+
+    let audio_len = end.saturating_sub(start);
+    info.duration =
+        Duration::try_from_secs_f64(audio_len as f64 * 8.0 / f64::from(frame.bitrate)).ok();
+    info.bitrate = Some(u64::from(frame.bitrate));
+
+    Ok(info)
+}
+
+/// Reads the frame and byte counts from a Xing, Info or VBRI header in the first frame.
+fn vbr_header(data: &[u8], frame: &Mp3Frame) -> Option<(Option<u32>, Option<u32>)> {
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+
+    let xing = frame.xing_offset();
+    if let Some(b"Xing" | b"Info") = data.get(xing..xing + 4) {
+        let flags = u32_at(xing + 4)?;
+        let frames = (flags & 1 != 0).then(|| u32_at(xing + 8)).flatten();
+        let bytes_offset = xing + if flags & 1 != 0 { 12 } else { 8 };
+        let bytes = (flags & 2 != 0).then(|| u32_at(bytes_offset)).flatten();
+
+        return Some((frames, bytes));
+    }
+
+    if let Some(b"VBRI") = data.get(36..40) {
+        return Some((u32_at(50), u32_at(46)));
+    }
+
+    None
+}
+
+/// Fields of an OGG page header needed for probing.
+struct OggPage {
+    granule: u64,
+    serial: u32,
+}
+
+impl OggPage {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if !data.starts_with(b"OggS") || data.len() < 27 {
+            return None;
+        }
+
+        Some(Self {
+            granule: u64::from_le_bytes(data[6..14].try_into().unwrap()),
+            serial: u32::from_le_bytes(data[14..18].try_into().unwrap()),
+        })
+    }
+}
+
+fn probe_ogg<R: Read + Seek>(reader: &mut R, length: u64) -> io::Result<MediaInfo> {
+    let head = read_at(reader, 0, 27 + 255 + 64)?;
+    let first = OggPage::parse(&head).ok_or_else(|| invalid("missing OGG page header"))?;
+    let packet = head.get(27 + head[26] as usize..).unwrap_or_default();
+
+    let mut info = MediaInfo::default();
+
+    // granule positions count samples at 48 kHz for Opus and at the stream rate for Vorbis
+    let (rate, pre_skip) = if packet.starts_with(b"OpusHead") && packet.len() >= 12 {
+        info.audio_codec = Some("opus".to_string());
+        (
+            48000,
+            u64::from(u16::from_le_bytes([packet[10], packet[11]])),
+        )
+    } else if packet.starts_with(b"\x01vorbis") && packet.len() >= 16 {
+        info.audio_codec = Some("vorbis".to_string());
+        (u32::from_le_bytes(packet[12..16].try_into().unwrap()), 0)
+    } else {
+        return Ok(info);
+    };
+
+    let tail_start = length.saturating_sub(OGG_TAIL_LEN);
+    let tail = read_at(reader, tail_start, OGG_TAIL_LEN)?;
+
+    let last = (0..tail.len().saturating_sub(3))
+        .rev()
+        .filter_map(|position| OggPage::parse(&tail[position..]))
+        .find(|page| page.serial == first.serial && page.granule != u64::MAX);
+
+    if let Some(page) = last
+        && rate != 0
+    {
+        let samples = page.granule.saturating_sub(pre_skip);
+        info.duration = Duration::try_from_secs_f64(samples as f64 / f64::from(rate)).ok();
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::bmff::{put_u16, put_u32, write_box, write_full_box};
+
+    fn mp4(duration: u32, width: u32, height: u32) -> Vec<u8> {
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", |out| {
+            out.extend_from_slice(b"isom\0\0\x02\0isom")
+        });
+
+        write_box(&mut file, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| {
+                out.extend_from_slice(&[0; 8]);
+                put_u32(out, 1000);
+                put_u32(out, duration);
+                out.extend_from_slice(&[0; 80]);
+            });
+
+            let trak = |out: &mut Vec<u8>, handler: &[u8; 4], codec: &[u8; 4]| {
+                write_box(out, b"trak", |out| {
+                    write_full_box(out, b"tkhd", 0, 3, |out| {
+                        out.extend_from_slice(&[0; 72]);
+                        put_u32(out, width << 16);
+                        put_u32(out, height << 16);
+                    });
+                    write_box(out, b"mdia", |out| {
+                        write_full_box(out, b"hdlr", 0, 0, |out| {
+                            put_u32(out, 0);
+                            out.extend_from_slice(handler);
+                            out.extend_from_slice(&[0; 13]);
+                        });
+                        write_box(out, b"minf", |out| {
+                            write_box(out, b"stbl", |out| {
+                                write_full_box(out, b"stsd", 0, 0, |out| {
+                                    put_u32(out, 1);
+                                    write_box(out, codec, |out| {
+                                        out.extend_from_slice(&[0; 24]);
+                                        put_u16(out, 0);
+                                        put_u16(out, 0);
+                                    });
+                                });
+                            });
+                        });
+                    });
+                });
+            };
+            trak(out, b"vide", b"avc1");
+            trak(out, b"soun", b"mp4a");
+        });
+
+        write_box(&mut file, b"mdat", |out| out.extend_from_slice(&[0; 100]));
+        file
+    }
+
+    #[test]
+    fn test_probe_mp4() {
+        let info = probe(&mp4(2500, 1280, 720)).unwrap();
+
+        assert_eq!(info.file_type, Some(Type::MP4));
+        assert_eq!(info.duration, Some(Duration::from_millis(2500)));
+        assert_eq!((info.width, info.height), (Some(1280), Some(720)));
+        assert_eq!(info.video_codec.as_deref(), Some("avc1"));
+        assert_eq!(info.audio_codec.as_deref(), Some("mp4a"));
+        assert!(info.bitrate.is_some());
+
+        // the largest 16.16 sizes round up instead of overflowing
+        let mut tkhd = vec![0; 76];
+        tkhd.extend_from_slice(&[0xff; 8]);
+        assert_eq!(tkhd_size(&tkhd).unwrap(), (0x1_0000, 0x1_0000));
+    }
+
+    #[test]
+    fn test_probe_corrupt_box_sizes() {
+        // a trailing box whose 64-bit size wraps around past the end of the file
+        let mut file = mp4(2500, 1280, 720);
+        put_u32(&mut file, 1);
+        file.extend_from_slice(b"free");
+        file.extend_from_slice(&(u64::MAX - 8).to_be_bytes());
+        assert!(probe(&file).is_err());
+
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", |out| {
+            out.extend_from_slice(b"isom\0\0\x02\0isom")
+        });
+        write_box(&mut file, b"moov", |out| {
+            put_u32(out, 1);
+            out.extend_from_slice(b"trak");
+            out.extend_from_slice(&u64::MAX.to_be_bytes());
+        });
+        assert!(probe(&file).is_err());
+
+        // durations too long for a `Duration` are left out
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", |out| {
+            out.extend_from_slice(b"isom\0\0\x02\0isom")
+        });
+        write_box(&mut file, b"moov", |out| {
+            write_full_box(out, b"mvhd", 1, 0, |out| {
+                out.extend_from_slice(&[0; 16]);
+                put_u32(out, 1);
+                out.extend_from_slice(&(u64::MAX - 1).to_be_bytes());
+                out.extend_from_slice(&[0; 80]);
+            });
+        });
+        assert_eq!(probe(&file).unwrap().duration, None);
+
+        let page = |granule: u64, packet: &[u8]| {
+            let mut page = b"OggS\0\0".to_vec();
+            page.extend_from_slice(&granule.to_le_bytes());
+            page.extend_from_slice(&7u32.to_le_bytes());
+            page.extend_from_slice(&[0; 8]);
+            page.push(1);
+            page.push(packet.len() as u8);
+            page.extend_from_slice(packet);
+            page
+        };
+        let mut head = b"\x01vorbis\0\0\0\0\x02".to_vec();
+        head.extend_from_slice(&1u32.to_le_bytes());
+        head.extend_from_slice(&[0; 14]);
+        let mut file = page(0, &head);
+        file.extend_from_slice(&page(u64::MAX - 1, &[0; 40]));
+        assert_eq!(probe(&file).unwrap().duration, None);
+    }
+
+    #[test]
+    fn test_probe_matroska() {
+        let element = |id: &[u8], data: &[u8]| {
+            let mut out = id.to_vec();
+            out.push(0x80 | data.len() as u8);
+            out.extend_from_slice(data);
+            out
+        };
+
+        let info = element(
+            &[0x15, 0x49, 0xa9, 0x66],
+            &[
+                element(&[0x2a, 0xd7, 0xb1], &[0x0f, 0x42, 0x40]),
+                element(&[0x44, 0x89], &3000f32.to_be_bytes()),
+            ]
+            .concat(),
+        );
+        let video = element(
+            &[0xae],
+            &[
+                element(&[0x83], &[1]),
+                element(&[0x86], b"V_VP9"),
+                element(
+                    &[0xe0],
+                    &[
+                        element(&[0xb0], &[0x02, 0x80]),
+                        element(&[0xba], &[0x01, 0x68]),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        let audio = element(
+            &[0xae],
+            &[element(&[0x83], &[2]), element(&[0x86], b"A_OPUS")].concat(),
+        );
+        let tracks = element(&[0x16, 0x54, 0xae, 0x6b], &[video, audio].concat());
+
+        let mut file = element(&[0x1a, 0x45, 0xdf, 0xa3], &element(&[0x42, 0x82], b"webm"));
+        // a segment of unknown size
+        file.extend_from_slice(&[
+            0x18, 0x53, 0x80, 0x67, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ]);
+        file.extend_from_slice(&info);
+        file.extend_from_slice(&tracks);
+        file.extend_from_slice(&element(&[0x1f, 0x43, 0xb6, 0x75], &[0; 16]));
+
+        let info = probe(&file).unwrap();
+        assert_eq!(info.file_type, Some(Type::WEBM));
+        assert_eq!(info.duration, Some(Duration::from_secs(3)));
+        assert_eq!((info.width, info.height), (Some(640), Some(360)));
+        assert_eq!(info.video_codec.as_deref(), Some("V_VP9"));
+        assert_eq!(info.audio_codec.as_deref(), Some("A_OPUS"));
+    }
+
+    #[test]
+    fn test_probe_mp3() {
+        // MPEG-1 layer III, 128 kbit/s, 44.1 kHz, joint stereo
+        let header = [0xff, 0xfb, 0x90, 0x44];
+
+        let mut cbr = b"ID3\x04\0\0\0\0\0\x0a".to_vec();
+        cbr.extend_from_slice(&[0; 10]);
+        for _ in 0..100 {
+            cbr.extend_from_slice(&header);
+            cbr.extend_from_slice(&[0; 413]);
+        }
+        let info = probe(&cbr).unwrap();
+        assert_eq!(info.bitrate, Some(128_000));
+        assert_eq!(
+            info.duration,
+            Some(Duration::from_secs_f64(41700.0 * 8.0 / 128_000.0))
+        );
+
+        let mut vbr = header.to_vec();
+        vbr.extend_from_slice(&[0; 32]);
+        vbr.extend_from_slice(b"Xing");
+        vbr.extend_from_slice(&3u32.to_be_bytes());
+        vbr.extend_from_slice(&1000u32.to_be_bytes());
+        vbr.extend_from_slice(&200_000u32.to_be_bytes());
+        vbr.resize(417, 0);
+        let info = probe(&vbr).unwrap();
+        let duration = 1000.0 * 1152.0 / 44100.0;
+        assert_eq!(info.duration, Some(Duration::from_secs_f64(duration)));
+        assert_eq!(info.bitrate, Some((200_000.0 * 8.0 / duration) as u64));
+    }
+
+    #[test]
+    fn test_probe_ogg() {
+        let page = |granule: u64, packet: &[u8]| {
+            let mut page = b"OggS\0\0".to_vec();
+            page.extend_from_slice(&granule.to_le_bytes());
+            page.extend_from_slice(&7u32.to_le_bytes());
+            page.extend_from_slice(&[0; 8]);
+            page.push(1);
+            page.push(packet.len() as u8);
+            page.extend_from_slice(packet);
+            page
+        };
+
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&[0; 7]);
+
+        let mut file = page(0, &head);
+        file.extend_from_slice(&page(u64::MAX, &[0; 40]));
+        file.extend_from_slice(&page(48000 * 5 + 312, &[0; 40]));
+
+        let info = probe(&file).unwrap();
+        assert_eq!(info.file_type, Some(Type::OPUS));
+        assert_eq!(info.audio_codec.as_deref(), Some("opus"));
+        assert_eq!(info.duration, Some(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+/// Minimum time between two samples of the instantaneous throughput.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Snapshot of a download in progress.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Progress {
+    /// Bytes received so far, including bytes resumed from a partial file.
+    pub received: u64,
+    /// Total size of the file, if the server sent a `Content-Length`.
+    pub total: Option<u64>,
+    /// Throughput over the last sample interval, in bytes per second.
+    pub speed: f64,
+    /// Throughput since the download started, in bytes per second.
+    pub average_speed: f64,
+    /// Whether the download has finished.
+    pub done: bool,
+}
+
+impl Progress {
+    /// Returns the completed percentage, if the total size is known.
+    #[must_use]
+    pub fn percent(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(100.0),
+            Some(total) => Some(self.received as f64 / total as f64 * 100.0),
+            None => None,
+        }
+    }
+}
+
+pub type ProgressSender = watch::Sender<Progress>;
+pub type ProgressReceiver = watch::Receiver<Progress>;
+
+/// Creates a channel to receive download progress updates.
+pub fn channel() -> (ProgressSender, ProgressReceiver) {
+    watch::channel(Progress::default())
+}
+
+/// Keeps track of received bytes and publishes [`Progress`] updates.
+#[derive(Debug)]
+pub(crate) struct ProgressTracker<'a> {
+    sender: Option<&'a ProgressSender>,
+    progress: Progress,
+    started: Instant,
+    resumed: u64,
+    sampled_at: Instant,
+    sampled: u64,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub(crate) fn new(
+        sender: Option<&'a ProgressSender>,
+        resumed: u64,
+        total: Option<u64>,
+    ) -> Self {
+        // keep a total published earlier, e.g. by a HEAD request, if the response lacks one
+        let total = total.or_else(|| sender.and_then(|sender| sender.borrow().total));
+
+        let now = Instant::now();
+        let tracker = Self {
+            sender,
+            progress: Progress {
+                received: resumed,
+                total,
+                ..Default::default()
+            },
+            started: now,
+            resumed,
+            sampled_at: now,
+            sampled: resumed,
+        };
+        tracker.publish();
+        tracker
+    }
+
+    pub(crate) fn advance(&mut self, bytes: u64) {
+        self.progress.received += bytes;
+
+        let now = Instant::now();
+        let since_sample = now.duration_since(self.sampled_at);
+        if since_sample >= SAMPLE_INTERVAL {
+            self.progress.speed =
+                (self.progress.received - self.sampled) as f64 / since_sample.as_secs_f64();
+            self.sampled_at = now;
+            self.sampled = self.progress.received;
+        }
+
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        if elapsed > 0.0 {
+            self.progress.average_speed = (self.progress.received - self.resumed) as f64 / elapsed;
+        }
+
+        self.publish();
+    }
+
+    pub(crate) fn finish(&mut self) {
+        self.progress.done = true;
+        if self.progress.total.is_none() {
+            self.progress.total = Some(self.progress.received);
+        }
+        self.publish();
+    }
+
+    fn publish(&self) {
+        if let Some(sender) = self.sender {
+            sender.send_replace(self.progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent() {
+        let progress = Progress {
+            received: 25,
+            total: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(progress.percent(), Some(25.0));
+        assert_eq!(Progress::default().percent(), None);
+    }
+
+    #[test]
+    fn test_tracker_publishes() {
+        let (tx, rx) = channel();
+        let mut tracker = ProgressTracker::new(Some(&tx), 10, Some(40));
+        assert_eq!(rx.borrow().received, 10);
+
+        tracker.advance(30);
+        assert_eq!(rx.borrow().received, 40);
+        assert_eq!(rx.borrow().percent(), Some(100.0));
+
+        tracker.finish();
+        assert!(rx.borrow().done);
+    }
+}
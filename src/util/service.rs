@@ -0,0 +1,121 @@
+use url::Url;
+
+/// Known hosts for each service, using the names cobalt reports in `InfoResponse`.
+const SERVICES: &[(&str, &[&str])] = &[
+    ("bilibili", &["bilibili.com", "bilibili.tv", "b23.tv"]),
+    ("bluesky", &["bsky.app"]),
+    ("dailymotion", &["dailymotion.com", "dai.ly"]),
+    ("facebook", &["facebook.com", "fb.com", "fb.watch"]),
+    ("instagram", &["instagram.com", "ddinstagram.com"]),
+    ("loom", &["loom.com"]),
+    ("newgrounds", &["newgrounds.com"]),
+    ("ok", &["ok.ru"]),
+    ("pinterest", &["pinterest.com", "pin.it"]),
+    ("reddit", &["reddit.com", "redd.it"]),
+    ("rutube", &["rutube.ru"]),
+    ("snapchat", &["snapchat.com"]),
+    ("soundcloud", &["soundcloud.com", "soundcloud.app.goo.gl"]),
+    ("streamable", &["streamable.com"]),
+    ("tiktok", &["tiktok.com"]),
+    ("tumblr", &["tumblr.com"]),
+    ("twitch clips", &["twitch.tv"]),
+    (
+        "twitter",
+        &[
+            "twitter.com",
+            "x.com",
+            "vxtwitter.com",
+            "fxtwitter.com",
+            "fixvx.com",
+        ],
+    ),
+    ("vimeo", &["vimeo.com"]),
+    ("vk", &["vk.com", "vk.ru", "vkvideo.ru"]),
+    ("xiaohongshu", &["xiaohongshu.com", "xhslink.com"]),
+    (
+        "youtube",
+        &["youtube.com", "youtu.be", "youtube-nocookie.com"],
+    ),
+];
+
+/// Public suffixes of the country domains pinterest serves, e.g. `pinterest.co.uk`.
+const PINTEREST_SUFFIXES: &[&str] = &[
+    "at", "ca", "ch", "cl", "co.kr", "co.uk", "com", "com.au", "com.mx", "de", "dk", "es", "fr",
+    "ie", "it", "jp", "nz", "ph", "pt", "ru", "se",
+];
+
+/// Returns whether `host` is `domain` or one of its subdomains.
+fn matches_domain(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Detects which cobalt service a media URL belongs to.
+///
+/// Returns the service name as listed in `CobaltInfo.services`, or `None` if the host is unknown.
+#[must_use]
+pub fn detect_service(url: &str) -> Option<&'static str> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?.trim_end_matches('.').to_ascii_lowercase();
+
+    if PINTEREST_SUFFIXES
+        .iter()
+        .any(|suffix| matches_domain(&host, &format!("pinterest.{suffix}")))
+    {
+        return Some("pinterest");
+    }
+
+    SERVICES.iter().find_map(|(service, domains)| {
+        domains
+            .iter()
+            .any(|domain| matches_domain(&host, domain))
+            .then_some(*service)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_service() {
+        assert_eq!(
+            detect_service("https://www.youtube.com/watch?v=dQw4w9WgXcQ"),
+            Some("youtube")
+        );
+        assert_eq!(
+            detect_service("https://youtu.be/dQw4w9WgXcQ"),
+            Some("youtube")
+        );
+        assert_eq!(
+            detect_service("https://x.com/user/status/1"),
+            Some("twitter")
+        );
+        assert_eq!(detect_service("https://vm.tiktok.com/abc/"), Some("tiktok"));
+        assert_eq!(detect_service("https://v.redd.it/abc"), Some("reddit"));
+        assert_eq!(
+            detect_service("https://pinterest.co.uk/pin/1/"),
+            Some("pinterest")
+        );
+        assert_eq!(
+            detect_service("https://www.pinterest.com.au/pin/1/"),
+            Some("pinterest")
+        );
+        assert_eq!(
+            detect_service("https://clips.twitch.tv/abc"),
+            Some("twitch clips")
+        );
+    }
+
+    #[test]
+    fn test_detect_service_unknown() {
+        assert_eq!(detect_service("https://example.com/video"), None);
+        assert_eq!(detect_service("https://notyoutube.com/watch"), None);
+        assert_eq!(detect_service("https://pinterest.evil.com/pin/1/"), None);
+        assert_eq!(detect_service("https://pinterest.example.org/"), None);
+        assert_eq!(detect_service("https://notpinterest.com/"), None);
+        assert_eq!(detect_service("not a url"), None);
+    }
+}
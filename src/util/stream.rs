@@ -1,12 +1,158 @@
+use std::fmt;
 use std::sync::Arc;
 
 use futures::StreamExt;
-use reqwest::Client;
+use reqwest::header::{
+    ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED,
+    RANGE,
+};
+use reqwest::{Client, Response, StatusCode};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use url::Url;
 
+use crate::util::filetype::{self, Type};
+use crate::util::progress::{ProgressSender, ProgressTracker};
+
+/// Number of leading bytes kept aside for file type detection.
+pub(crate) const SNIFF_LEN: usize = 64;
+
+/// Error returned when streaming a response body into a writer.
+#[derive(Debug)]
+pub enum StreamError {
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    /// The file is larger than the allowed maximum. `size` is the announced size if the
+    /// server sent one, or the number of bytes received when the limit was passed.
+    TooLarge {
+        size: u64,
+        max_bytes: u64,
+    },
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Http(err) => write!(f, "http error: {err}"),
+            StreamError::Io(err) => write!(f, "io error: {err}"),
+            StreamError::TooLarge { size, max_bytes } => {
+                write!(
+                    f,
+                    "file of {size} bytes exceeds the limit of {max_bytes} bytes"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::Http(err) => Some(err),
+            StreamError::Io(err) => Some(err),
+            StreamError::TooLarge { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for StreamError {
+    fn from(err: reqwest::Error) -> Self {
+        StreamError::Http(err)
+    }
+}
+
+impl From<std::io::Error> for StreamError {
+    fn from(err: std::io::Error) -> Self {
+        StreamError::Io(err)
+    }
+}
+
+/// Metadata of a download response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseInfo {
+    /// URL the body was served from, after following redirects.
+    pub url: Url,
+    /// Value of the `Content-Type` header.
+    pub content_type: Option<String>,
+    /// Size of the whole file, taken from `Content-Range` for partial responses.
+    pub content_length: Option<u64>,
+    /// File name from the `Content-Disposition` header, see [`disposition_filename`].
+    pub filename: Option<String>,
+    /// File type detected from the first bytes of the body, once it has been read.
+    pub file_type: Option<Type>,
+}
+
+impl ResponseInfo {
+    /// Collects the metadata from the response headers.
+    pub fn from_response(response: &Response) -> Self {
+        let headers = response.headers();
+
+        let total = headers
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok());
+
+        Self {
+            url: response.url().clone(),
+            content_type: headers
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            content_length: total.or_else(|| {
+                (response.status() != StatusCode::PARTIAL_CONTENT)
+                    .then(|| response.content_length())
+                    .flatten()
+            }),
+            filename: disposition_filename(response),
+            file_type: None,
+        }
+    }
+
+    /// Picks the extension for the file, without the leading dot.
+    ///
+    /// In order of precedence, the extension comes from:
+    /// 1. `filename`, the name suggested by cobalt, which knows what it produced;
+    /// 2. the magic bytes in [`ResponseInfo::file_type`], as servers may send wrong headers;
+    /// 3. the `Content-Disposition` file name;
+    /// 4. the `Content-Type`, see [`Type::from_mime`];
+    ///
+    /// falling back to `bin`.
+    #[must_use]
+    pub fn extension(&self, filename: Option<&str>) -> String {
+        let from_name = |name: &str| {
+            std::path::Path::new(name)
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        };
+
+        filename
+            .and_then(from_name)
+            .or_else(|| self.file_type.map(|t| t.as_str().to_string()))
+            .or_else(|| self.filename.as_deref().and_then(from_name))
+            .or_else(|| {
+                self.content_type
+                    .as_deref()
+                    .and_then(Type::from_mime)
+                    .map(|t| t.as_str().to_string())
+            })
+            .unwrap_or_else(|| "bin".to_string())
+    }
+}
+
 /// Reads a stream from the given URL and returns the full response body as bytes.
 pub async fn read_stream(client: Arc<Client>, url: Url) -> Result<Vec<u8>, reqwest::Error> {
+    read_stream_with_info(client, url)
+        .await
+        .map(|(data, _)| data)
+}
+
+/// Same as [`read_stream`], also returning the response metadata.
+pub async fn read_stream_with_info(
+    client: Arc<Client>,
+    url: Url,
+) -> Result<(Vec<u8>, ResponseInfo), reqwest::Error> {
     let response = client.get(url).send().await?;
+    let mut info = ResponseInfo::from_response(&response);
 
     let mut stream = response.bytes_stream();
     let mut data = Vec::new();
@@ -16,5 +162,308 @@ pub async fn read_stream(client: Arc<Client>, url: Url) -> Result<Vec<u8>, reqwe
         data.extend_from_slice(&bytes);
     }
 
-    Ok(data)
+    info.file_type = filetype::get_sig(&data);
+    Ok((data, info))
+}
+
+/// Writes the response body from the given URL into `writer` as each chunk arrives.
+///
+/// Only the first few bytes are kept in memory to detect the file type, which is returned
+/// with the response metadata once the whole body has been written. Progress updates are
+/// published to `progress`, if set.
+pub async fn write_stream<W>(
+    client: Arc<Client>,
+    url: Url,
+    writer: &mut W,
+    progress: Option<&ProgressSender>,
+) -> Result<ResponseInfo, StreamError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let response = open_stream(client, url, 0).await?.error_for_status()?;
+    let mut info = ResponseInfo::from_response(&response);
+
+    let head = copy_body(response, writer, 0, progress, None).await?;
+    info.file_type = filetype::get_sig(&head);
+
+    Ok(info)
+}
+
+/// Sends a GET request for the given URL.
+///
+/// When `offset` is non-zero, a `Range: bytes=offset-` header is sent so that the server
+/// can continue an interrupted download. Servers that ignore the range reply with `200 OK`
+/// and the full body, which callers should check for via the response status.
+pub async fn open_stream(
+    client: Arc<Client>,
+    url: Url,
+    offset: u64,
+) -> Result<Response, reqwest::Error> {
+    open_range(client, url, offset, None).await
+}
+
+/// Same as [`open_stream`], with an `If-Range` validator so that the server sends the
+/// full body instead of a range when the file changed since the validator was recorded.
+pub(crate) async fn open_range(
+    client: Arc<Client>,
+    url: Url,
+    offset: u64,
+    if_range: Option<&str>,
+) -> Result<Response, reqwest::Error> {
+    let mut req = client.get(url);
+
+    if offset > 0 {
+        req = req.header(RANGE, format!("bytes={offset}-"));
+
+        if let Some(validator) = if_range {
+            req = req.header(IF_RANGE, validator);
+        }
+    }
+
+    req.send().await
+}
+
+/// Returns the validator to send in `If-Range` when resuming the download of a response:
+/// its strong `ETag`, or else its `Last-Modified` date.
+pub(crate) fn range_validator(response: &Response) -> Option<String> {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    header(ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(LAST_MODIFIED))
+        .map(str::to_string)
+}
+
+/// Returns the first byte position and the total size from the `Content-Range` header
+/// of a partial response. The total is `None` when the server does not know it.
+pub(crate) fn content_range(response: &Response) -> Option<(u64, Option<u64>)> {
+    response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_content_range)
+}
+
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    if end.trim().parse::<u64>().ok()? < start {
+        return None;
+    }
+
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+
+    Some((start, total))
+}
+
+/// Returns whether the server advertised support for byte range requests.
+pub fn accepts_ranges(response: &Response) -> bool {
+    response
+        .headers()
+        .get(ACCEPT_RANGES)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("bytes"))
+        || response.status() == StatusCode::PARTIAL_CONTENT
+}
+
+/// Returns the file name from the `Content-Disposition` header of a response, if any.
+///
+/// The RFC 8187 `filename*` parameter is preferred over the plain `filename` one.
+/// The name is returned as sent, callers should sanitize it before use.
+pub fn disposition_filename(response: &Response) -> Option<String> {
+    let header = response.headers().get(CONTENT_DISPOSITION)?;
+    parse_disposition(&String::from_utf8_lossy(header.as_bytes()))
+}
+
+fn parse_disposition(header: &str) -> Option<String> {
+    let mut plain = None;
+
+    for param in header.split(';').skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                // charset'language'percent-encoded value
+                let mut parts = value.trim().splitn(3, '\'');
+                let charset = parts.next()?;
+                let encoded = parts.nth(1)?;
+                let bytes = percent_decode(encoded.trim_matches('"'))?;
+
+                if charset.eq_ignore_ascii_case("utf-8") {
+                    return String::from_utf8(bytes).ok();
+                }
+                // ISO-8859-1 maps bytes to the same code points
+                return Some(bytes.into_iter().map(char::from).collect());
+            }
+            "filename" => {
+                let value = value.trim();
+                plain = Some(match value.strip_prefix('"') {
+                    Some(quoted) => unquote(quoted),
+                    None => value.to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    plain.filter(|name| !name.is_empty())
+}
+
+/// Unescapes a quoted string, stopping at the closing quote.
+fn unquote(quoted: &str) -> String {
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next()),
+            '"' => break,
+            c => value.push(c),
+        }
+    }
+
+    value
+}
+
+fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Writes the body of `response` into `writer` as each chunk arrives.
+///
+/// `resumed` is the number of bytes already downloaded before this response, which is
+/// taken into account when publishing progress updates to `progress`.
+///
+/// With `max_bytes` set, nothing is read if the announced size is over the limit, and the
+/// download stops as soon as more bytes arrive, with [`StreamError::TooLarge`].
+///
+/// Returns the first bytes of the body for file type detection.
+pub async fn copy_body<W>(
+    response: Response,
+    writer: &mut W,
+    resumed: u64,
+    progress: Option<&ProgressSender>,
+    max_bytes: Option<u64>,
+) -> Result<Vec<u8>, StreamError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let total = response.content_length().map(|len| len + resumed);
+    if let (Some(size), Some(max_bytes)) = (total, max_bytes)
+        && size > max_bytes
+    {
+        return Err(StreamError::TooLarge { size, max_bytes });
+    }
+
+    let mut received = resumed;
+    let mut tracker = ProgressTracker::new(progress, resumed, total);
+
+    let mut stream = response.bytes_stream();
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+
+    while let Some(chunk) = stream.next().await {
+        let bytes = chunk?;
+
+        received += bytes.len() as u64;
+        if let Some(max_bytes) = max_bytes
+            && received > max_bytes
+        {
+            return Err(StreamError::TooLarge {
+                size: received,
+                max_bytes,
+            });
+        }
+
+        if head.len() < SNIFF_LEN {
+            let take = (SNIFF_LEN - head.len()).min(bytes.len());
+            head.extend_from_slice(&bytes[..take]);
+        }
+
+        writer.write_all(&bytes).await?;
+        tracker.advance(bytes.len() as u64);
+    }
+
+    writer.flush().await?;
+    tracker.finish();
+
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_disposition() {
+        assert_eq!(
+            parse_disposition(r#"attachment; filename="video \"1\".mp4""#).as_deref(),
+            Some(r#"video "1".mp4"#)
+        );
+        assert_eq!(
+            parse_disposition("attachment; filename=plain.mp3").as_deref(),
+            Some("plain.mp3")
+        );
+        assert_eq!(
+            parse_disposition(
+                "attachment; filename=\"fallback.mp4\"; filename*=UTF-8''%D0%B2%D0%B8%D0%B4%D0%B5%D0%BE.mp4"
+            )
+            .as_deref(),
+            Some("видео.mp4")
+        );
+        assert_eq!(parse_disposition("inline"), None);
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 100-199/1000"),
+            Some((100, Some(1000)))
+        );
+        assert_eq!(parse_content_range("bytes 100-199/*"), Some((100, None)));
+        assert_eq!(parse_content_range("bytes */1000"), None);
+        assert_eq!(parse_content_range("bytes 200-100/1000"), None);
+    }
+
+    #[test]
+    fn test_extension_precedence() {
+        let mut info = ResponseInfo {
+            url: Url::parse("https://example.com/tunnel").unwrap(),
+            content_type: Some("audio/ogg; codecs=opus".to_string()),
+            content_length: None,
+            filename: Some("track.OGG".to_string()),
+            file_type: Some(Type::MP4),
+        };
+
+        assert_eq!(info.extension(Some("video.webm")), "webm");
+        assert_eq!(info.extension(None), "mp4");
+        info.file_type = None;
+        assert_eq!(info.extension(None), "ogg");
+        info.filename = None;
+        assert_eq!(info.extension(None), "opus");
+        info.content_type = Some("application/octet-stream".to_string());
+        assert_eq!(info.extension(Some("no extension")), "bin");
+    }
 }
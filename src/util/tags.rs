@@ -0,0 +1,930 @@
+//! Writing of [`OutputMetadata`] into saved media files.
+//!
+//! ID3v2 frames are written to MP3 files, Vorbis comments to OGG (Vorbis and Opus) files
+//! and `ilst` atoms to MP4/M4A files. Existing tags are kept, except for the fields that
+//! are replaced.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::model::response::OutputMetadata;
+use crate::util::bmff::{self, put_u16, put_u32, write_box, write_full_box};
+use crate::util::filetype;
+
+/// Tag formats supported by [`write_tags`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagFormat {
+    /// ID3v2, used by MP3.
+    Id3,
+    /// Vorbis comments, used by OGG Vorbis and Opus.
+    VorbisComment,
+    /// iTunes-style `ilst` atoms, used by MP4 and M4A.
+    Ilst,
+}
+
+impl TagFormat {
+    /// Detects the tag format from the first bytes of a file.
+    ///
+    /// Untagged MPEG audio gets an ID3v2 tag, ADTS AAC is left alone.
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        match head {
+            [b'I', b'D', b'3', ..] => Some(TagFormat::Id3),
+            _ if filetype::mpeg_layer(head).is_some() => Some(TagFormat::Id3),
+            [b'O', b'g', b'g', b'S', ..] => Some(TagFormat::VorbisComment),
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(TagFormat::Ilst),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the metadata into the file at `path`.
+///
+/// The file is rewritten through a temporary file next to it. Returns `false` without
+/// touching the file if its format has no supported tags, or the metadata is empty.
+pub fn write_tags(path: impl AsRef<Path>, metadata: &OutputMetadata) -> io::Result<bool> {
+    let path = path.as_ref();
+    if fields(metadata).next().is_none() {
+        return Ok(false);
+    }
+
+    let mut input = BufReader::new(File::open(path)?);
+    let length = input.get_ref().metadata()?.len();
+
+    let mut head = [0; 12];
+    let read = input.read(&mut head)?;
+    let Some(format) = TagFormat::detect(&head[..read]) else {
+        return Ok(false);
+    };
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tags");
+    let temp = path.with_file_name(temp_name);
+
+    let result = (|| {
+        let mut output = BufWriter::new(File::create(&temp)?);
+        match format {
+            TagFormat::Id3 => write_id3(&mut input, &mut output, metadata)?,
+            TagFormat::VorbisComment => write_vorbis(&mut input, &mut output, metadata)?,
+            TagFormat::Ilst => write_ilst(&mut input, &mut output, length, metadata)?,
+        }
+        output
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()
+    })();
+
+    match result {
+        Ok(()) => {
+            std::fs::rename(&temp, path)?;
+            Ok(true)
+        }
+        Err(err) => {
+            let _ = std::fs::remove_file(&temp);
+            Err(err)
+        }
+    }
+}
+
+/// A metadata field, independent of the tag format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Title,
+    Artist,
+    Album,
+    Track,
+    Date,
+    Copyright,
+}
+
+fn fields(metadata: &OutputMetadata) -> impl Iterator<Item = (Field, &str)> {
+    [
+        (Field::Title, &metadata.title),
+        (Field::Artist, &metadata.artist),
+        (Field::Album, &metadata.album),
+        (Field::Track, &metadata.track),
+        (Field::Date, &metadata.date),
+        (Field::Copyright, &metadata.copyright),
+    ]
+    .into_iter()
+    .filter_map(|(field, value)| Some((field, value.as_deref()?.trim())))
+    .filter(|(_, value)| !value.is_empty())
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Copies everything from `offset` to the end of the input.
+fn copy_from<R: Read + Seek, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    offset: u64,
+) -> io::Result<()> {
+    input.seek(SeekFrom::Start(offset))?;
+    io::copy(input, output)?;
+    Ok(())
+}
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |value, byte| (value << 7) | u32::from(byte & 0x7F))
+}
+
+fn to_syncsafe(value: u32) -> [u8; 4] {
+    [
+        (value >> 21) as u8 & 0x7F,
+        (value >> 14) as u8 & 0x7F,
+        (value >> 7) as u8 & 0x7F,
+        value as u8 & 0x7F,
+    ]
+}
+
+/// An ID3v2 frame, kept as is when the tag is rewritten.
+struct Frame {
+    id: [u8; 4],
+    flags: [u8; 2],
+    data: Vec<u8>,
+}
+
+/// Replaces the ID3v2 tag at the start of an MP3 file, or adds one.
+///
+/// An existing ID3v2.4 tag stays at version 4, anything else is written as ID3v2.3.
+fn write_id3<R: Read + Seek, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    metadata: &OutputMetadata,
+) -> io::Result<()> {
+    input.seek(SeekFrom::Start(0))?;
+    let mut header = [0; 10];
+    let has_tag = input.read_exact(&mut header).is_ok() && header.starts_with(b"ID3");
+
+    let (version, mut frames, audio_start) = if has_tag {
+        let (major, flags) = (header[3], header[5]);
+        let size = syncsafe(&header[6..10]);
+        let mut body = vec![0; size as usize];
+        input.read_exact(&mut body)?;
+
+        let footer = if major == 4 && flags & 0x10 != 0 {
+            10
+        } else {
+            0
+        };
+        // unsynchronised tags and extended headers are dropped instead of parsed
+        let frames = if matches!(major, 3 | 4) && flags & 0xC0 == 0 {
+            read_frames(&body, major)
+        } else {
+            Vec::new()
+        };
+
+        (
+            if major == 4 { 4 } else { 3 },
+            frames,
+            10 + u64::from(size) + footer,
+        )
+    } else {
+        (3, Vec::new(), 0)
+    };
+
+    let new_frames: Vec<Frame> = fields(metadata)
+        .filter_map(|(field, value)| id3_frame(version, field, value))
+        .collect();
+
+    let replaces_date = fields(metadata).any(|(field, _)| field == Field::Date);
+    frames.retain(|frame| {
+        let replaced = new_frames.iter().any(|new| new.id == frame.id)
+            || replaces_date && matches!(&frame.id, b"TYER" | b"TDRC");
+        !replaced
+    });
+    frames.extend(new_frames);
+
+    let mut body = Vec::new();
+    for frame in &frames {
+        body.extend_from_slice(&frame.id);
+        let size = frame.data.len() as u32;
+        if version == 4 {
+            body.extend_from_slice(&to_syncsafe(size));
+        } else {
+            body.extend_from_slice(&size.to_be_bytes());
+        }
+        body.extend_from_slice(&frame.flags);
+        body.extend_from_slice(&frame.data);
+    }
+
+    output.write_all(b"ID3")?;
+    output.write_all(&[version, 0, 0])?;
+    output.write_all(&to_syncsafe(body.len() as u32))?;
+    output.write_all(&body)?;
+
+    copy_from(input, output, audio_start)
+}
+
+fn read_frames(body: &[u8], major: u8) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut pos = 0;
+
+    while pos + 10 <= body.len() && body[pos] != 0 {
+        let id: [u8; 4] = body[pos..pos + 4].try_into().unwrap();
+        let size = if major == 4 {
+            syncsafe(&body[pos + 4..pos + 8])
+        } else {
+            u32::from_be_bytes(body[pos + 4..pos + 8].try_into().unwrap())
+        } as usize;
+        let flags = [body[pos + 8], body[pos + 9]];
+
+        let Some(data) = body.get(pos + 10..pos + 10 + size) else {
+            break;
+        };
+        frames.push(Frame {
+            id,
+            flags,
+            data: data.to_vec(),
+        });
+        pos += 10 + size;
+    }
+
+    frames
+}
+
+fn id3_frame(version: u8, field: Field, value: &str) -> Option<Frame> {
+    let id = match field {
+        Field::Title => b"TIT2",
+        Field::Artist => b"TPE1",
+        Field::Album => b"TALB",
+        Field::Track => b"TRCK",
+        Field::Copyright => b"TCOP",
+        Field::Date if version == 4 => b"TDRC",
+        Field::Date => b"TYER",
+    };
+
+    // ID3v2.3 only has a year frame
+    let value = if id == b"TYER" {
+        let year = value.get(..4)?;
+        year.bytes()
+            .all(|byte| byte.is_ascii_digit())
+            .then_some(year)?
+    } else {
+        value
+    };
+
+    let data = if version == 4 {
+        let mut data = vec![0x03];
+        data.extend_from_slice(value.as_bytes());
+        data
+    } else {
+        let mut data = vec![0x01, 0xFF, 0xFE];
+        data.extend(value.encode_utf16().flat_map(u16::to_le_bytes));
+        data
+    };
+
+    Some(Frame {
+        id: *id,
+        flags: [0, 0],
+        data,
+    })
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static OGG_CRC: [u32; 256] = crc_table();
+
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, byte| {
+        (crc << 8) ^ OGG_CRC[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+/// An OGG page.
+struct Page {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Page {
+    fn read<R: Read>(input: &mut R) -> io::Result<Option<Self>> {
+        let mut header = [0; 27];
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        if &header[..4] != b"OggS" {
+            return Err(invalid("missing OGG page header"));
+        }
+
+        let mut lacing = vec![0; header[26] as usize];
+        input.read_exact(&mut lacing)?;
+        let mut data = vec![0; lacing.iter().map(|value| *value as usize).sum()];
+        input.read_exact(&mut data)?;
+
+        Ok(Some(Page {
+            header_type: header[5],
+            granule: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+            serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+            sequence: u32::from_le_bytes(header[18..22].try_into().unwrap()),
+            lacing,
+            data,
+        }))
+    }
+
+    fn write<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let mut page = Vec::with_capacity(27 + self.lacing.len() + self.data.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0);
+        page.push(self.header_type);
+        page.extend_from_slice(&self.granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(self.lacing.len() as u8);
+        page.extend_from_slice(&self.lacing);
+        page.extend_from_slice(&self.data);
+
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        output.write_all(&page)
+    }
+}
+
+/// Lays out header packets into pages, each packet starting on the page after the last.
+fn paginate(packets: &[Vec<u8>], serial: u32, first_sequence: u32, first_page: bool) -> Vec<Page> {
+    let mut pages = Vec::new();
+    let mut page = Page {
+        header_type: if first_page { 0x02 } else { 0 },
+        granule: 0,
+        serial,
+        sequence: first_sequence,
+        lacing: Vec::new(),
+        data: Vec::new(),
+    };
+
+    for packet in packets {
+        let mut rest = packet.as_slice();
+        loop {
+            let len = rest.len().min(255);
+            page.lacing.push(len as u8);
+            page.data.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+
+            let done = len < 255;
+            if page.lacing.len() == 255 {
+                let sequence = page.sequence + 1;
+                pages.push(std::mem::replace(
+                    &mut page,
+                    Page {
+                        header_type: if done { 0 } else { 0x01 },
+                        granule: 0,
+                        serial,
+                        sequence,
+                        lacing: Vec::new(),
+                        data: Vec::new(),
+                    },
+                ));
+            }
+            if done {
+                break;
+            }
+        }
+    }
+
+    if !page.lacing.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+/// Replaces the comment header of an OGG Vorbis or Opus file.
+fn write_vorbis<R: Read + Seek, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    metadata: &OutputMetadata,
+) -> io::Result<()> {
+    input.seek(SeekFrom::Start(0))?;
+
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut partial: Option<Vec<u8>> = None;
+    let mut header_pages = 0;
+    let mut expected = None;
+    let mut serial = None;
+
+    while expected.is_none_or(|count| packets.len() < count) {
+        let page = Page::read(input)?.ok_or_else(|| invalid("missing OGG header packets"))?;
+        if *serial.get_or_insert(page.serial) != page.serial {
+            return Err(invalid("multiplexed OGG streams are not supported"));
+        }
+        header_pages += 1;
+
+        let mut pos = 0;
+        for &len in &page.lacing {
+            let packet = partial.get_or_insert_with(Vec::new);
+            packet.extend_from_slice(&page.data[pos..pos + len as usize]);
+            pos += len as usize;
+
+            if len < 255 {
+                packets.extend(partial.take());
+            }
+        }
+
+        if expected.is_none() {
+            expected = match packets.first() {
+                Some(packet) if packet.starts_with(b"OpusHead") => Some(2),
+                Some(packet) if packet.starts_with(b"\x01vorbis") => Some(3),
+                Some(_) => return Err(invalid("unsupported OGG codec")),
+                None => None,
+            };
+        }
+    }
+
+    if partial.is_some() || expected.is_some_and(|count| packets.len() != count) {
+        return Err(invalid("audio data shares a page with the OGG headers"));
+    }
+
+    let serial = serial.unwrap_or_default();
+    let opus = packets[0].starts_with(b"OpusHead");
+    packets[1] = comment_packet(&packets[1], opus, metadata)?;
+
+    let mut pages = paginate(&packets[..1], serial, 0, true);
+    pages.extend(paginate(&packets[1..], serial, 1, false));
+    let delta = pages.len() as i64 - header_pages;
+
+    for page in &pages {
+        page.write(output)?;
+    }
+
+    while let Some(mut page) = Page::read(input)? {
+        page.sequence = (i64::from(page.sequence) + delta) as u32;
+        page.write(output)?;
+    }
+
+    Ok(())
+}
+
+fn vorbis_key(field: Field) -> &'static str {
+    match field {
+        Field::Title => "TITLE",
+        Field::Artist => "ARTIST",
+        Field::Album => "ALBUM",
+        Field::Track => "TRACKNUMBER",
+        Field::Date => "DATE",
+        Field::Copyright => "COPYRIGHT",
+    }
+}
+
+fn comment_packet(packet: &[u8], opus: bool, metadata: &OutputMetadata) -> io::Result<Vec<u8>> {
+    let magic: &[u8] = if opus { b"OpusTags" } else { b"\x03vorbis" };
+    let body = packet
+        .strip_prefix(magic)
+        .ok_or_else(|| invalid("missing OGG comment header"))?;
+
+    let truncated = || invalid("truncated OGG comment header");
+    let read_u32 = |pos: usize| -> io::Result<usize> {
+        body.get(pos..pos + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or_else(truncated)
+    };
+
+    let vendor_len = read_u32(0)?;
+    let vendor = body.get(4..4 + vendor_len).ok_or_else(truncated)?;
+    let count = read_u32(4 + vendor_len)?;
+
+    let mut pos = 8 + vendor_len;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = read_u32(pos)?;
+        comments.push(
+            body.get(pos + 4..pos + 4 + len)
+                .ok_or_else(truncated)?
+                .to_vec(),
+        );
+        pos += 4 + len;
+    }
+    // opus may carry binary data after the comments, vorbis ends with the framing bit
+    let trailing = if opus { &body[pos..] } else { &[][..] };
+
+    let new_comments: Vec<Vec<u8>> = fields(metadata)
+        .map(|(field, value)| format!("{}={value}", vorbis_key(field)).into_bytes())
+        .collect();
+
+    comments.retain(|comment| {
+        let key = comment
+            .split(|byte| *byte == b'=')
+            .next()
+            .unwrap_or_default();
+        !fields(metadata).any(|(field, _)| key.eq_ignore_ascii_case(vorbis_key(field).as_bytes()))
+    });
+    comments.extend(new_comments);
+
+    let mut packet = magic.to_vec();
+    packet.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    packet.extend_from_slice(vendor);
+    packet.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in &comments {
+        packet.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        packet.extend_from_slice(comment);
+    }
+    packet.extend_from_slice(trailing);
+    if !opus {
+        packet.push(1);
+    }
+
+    Ok(packet)
+}
+
+fn ilst_name(field: Field) -> &'static [u8; 4] {
+    match field {
+        Field::Title => b"\xA9nam",
+        Field::Artist => b"\xA9ART",
+        Field::Album => b"\xA9alb",
+        Field::Track => b"trkn",
+        Field::Date => b"\xA9day",
+        Field::Copyright => b"cprt",
+    }
+}
+
+/// Replaces the `moov/udta/meta` box of an MP4 file, moving the chunk offsets of media
+/// data stored after `moov`.
+///
+/// Fragmented files are expected to use offsets relative to `moof`, which do not move.
+fn write_ilst<R: Read + Seek, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    length: u64,
+    metadata: &OutputMetadata,
+) -> io::Result<()> {
+    let boxes = bmff::top_level(input, length)?;
+    let moov = boxes
+        .iter()
+        .find(|header| &header.kind == b"moov")
+        .ok_or_else(|| invalid("missing `moov` box"))?;
+
+    let payload = moov.read_payload(input)?;
+    let mut body = Vec::new();
+    rebuild_moov(&mut body, &payload, metadata)?;
+    let mut new_moov = Vec::new();
+    write_box(&mut new_moov, b"moov", |out| out.extend_from_slice(&body));
+
+    let delta = new_moov.len() as i64 - moov.size as i64;
+    if delta != 0 {
+        patch_chunk_offsets(&mut new_moov[8..], moov.offset + moov.size, delta)?;
+    }
+
+    input.seek(SeekFrom::Start(0))?;
+    io::copy(&mut input.by_ref().take(moov.offset), output)?;
+    output.write_all(&new_moov)?;
+    copy_from(input, output, moov.offset + moov.size)
+}
+
+fn rebuild_moov(out: &mut Vec<u8>, moov: &[u8], metadata: &OutputMetadata) -> io::Result<()> {
+    let children = bmff::children(moov)?;
+    let udta = children.iter().find(|(kind, _)| kind == b"udta");
+    let udta = udta.map(|(_, payload)| *payload).unwrap_or_default();
+    let udta_children = bmff::children(udta)?;
+
+    for (kind, payload) in &children {
+        if kind != b"udta" {
+            write_box(out, kind, |out| out.extend_from_slice(payload));
+        }
+    }
+
+    write_box(out, b"udta", |out| {
+        let mut old_items = Vec::new();
+
+        for (kind, payload) in &udta_children {
+            if kind == b"meta" {
+                // meta is a full box, skip its version and flags
+                let ilst = payload
+                    .get(4..)
+                    .and_then(|meta| bmff::child(meta, b"ilst").ok().flatten());
+                old_items = ilst
+                    .and_then(|ilst| bmff::children(ilst).ok())
+                    .unwrap_or_default();
+            } else {
+                write_box(out, kind, |out| out.extend_from_slice(payload));
+            }
+        }
+
+        write_full_box(out, b"meta", 0, 0, |out| {
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                put_u32(out, 0);
+                out.extend_from_slice(b"mdirappl");
+                out.extend_from_slice(&[0; 9]);
+            });
+
+            write_box(out, b"ilst", |out| {
+                for (kind, payload) in &old_items {
+                    if !fields(metadata).any(|(field, _)| ilst_name(field) == kind) {
+                        write_box(out, kind, |out| out.extend_from_slice(payload));
+                    }
+                }
+
+                for (field, value) in fields(metadata) {
+                    if let Some(data) = ilst_data(field, value) {
+                        write_box(out, ilst_name(field), |out| {
+                            write_box(out, b"data", |out| out.extend_from_slice(&data));
+                        });
+                    }
+                }
+            });
+        });
+    });
+
+    Ok(())
+}
+
+/// Returns the payload of the `data` box of an item: type, locale and value.
+fn ilst_data(field: Field, value: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+
+    if field == Field::Track {
+        let (number, total) = value.split_once('/').unwrap_or((value, "0"));
+        // implicit type
+        put_u32(&mut data, 0);
+        put_u32(&mut data, 0);
+        put_u16(&mut data, 0);
+        put_u16(&mut data, number.trim().parse().ok()?);
+        put_u16(&mut data, total.trim().parse().unwrap_or(0));
+        put_u16(&mut data, 0);
+    } else {
+        // UTF-8
+        put_u32(&mut data, 1);
+        put_u32(&mut data, 0);
+        data.extend_from_slice(value.as_bytes());
+    }
+
+    Some(data)
+}
+
+/// Adds `delta` to the `stco` and `co64` offsets that point at or after `from`.
+fn patch_chunk_offsets(moov: &mut [u8], from: u64, delta: i64) -> io::Result<()> {
+    for (kind, range) in bmff::child_ranges(moov)? {
+        match &kind {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => {
+                patch_chunk_offsets(&mut moov[range], from, delta)?
+            }
+            b"stco" | b"co64" => {
+                let table = &mut moov[range];
+                let width = if &kind == b"co64" { 8 } else { 4 };
+                let count = table
+                    .get(4..8)
+                    .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+                    .ok_or_else(|| invalid("truncated chunk offset box"))?;
+
+                for index in 0..count {
+                    let start = 8 + index * width;
+                    let entry = table
+                        .get_mut(start..start + width)
+                        .ok_or_else(|| invalid("truncated chunk offset box"))?;
+
+                    let offset = if width == 8 {
+                        u64::from_be_bytes(entry.try_into().unwrap())
+                    } else {
+                        u64::from(u32::from_be_bytes(entry.try_into().unwrap()))
+                    };
+                    if offset < from {
+                        continue;
+                    }
+
+                    let offset = offset.wrapping_add_signed(delta);
+                    if width == 8 {
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    } else {
+                        let offset = u32::try_from(offset)
+                            .map_err(|_| invalid("chunk offset does not fit in `stco`"))?;
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn metadata() -> OutputMetadata {
+        OutputMetadata {
+            title: Some("Título".to_string()),
+            artist: Some("Artist".to_string()),
+            track: Some("3/12".to_string()),
+            date: Some("2019-05-01".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(TagFormat::detect(b"ID3\x04"), Some(TagFormat::Id3));
+        assert_eq!(
+            TagFormat::detect(&[0xff, 0xfb, 0x90, 0x44]),
+            Some(TagFormat::Id3)
+        );
+        assert_eq!(TagFormat::detect(&[0xff, 0xf1, 0x50, 0x80]), None);
+        assert_eq!(TagFormat::detect(&[0xff, 0xf9, 0x50, 0x80]), None);
+        assert_eq!(
+            TagFormat::detect(b"OggS\0\x02"),
+            Some(TagFormat::VorbisComment)
+        );
+        assert_eq!(
+            TagFormat::detect(b"\0\0\0\x18ftypM4A "),
+            Some(TagFormat::Ilst)
+        );
+    }
+
+    #[test]
+    fn test_id3() {
+        // an existing tag with a title and a comment frame
+        let mut file = b"ID3\x03\x00\x00\x00\x00\x00\x1c".to_vec();
+        file.extend_from_slice(b"TIT2\x00\x00\x00\x04\x00\x00\x00old");
+        file.extend_from_slice(b"COMM\x00\x00\x00\x02\x00\x00\x00x");
+        file.extend_from_slice(&[0; 2]);
+        file.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+
+        let mut output = Vec::new();
+        write_id3(&mut Cursor::new(file), &mut output, &metadata()).unwrap();
+
+        assert!(output.starts_with(b"ID3\x03"));
+        let size = syncsafe(&output[6..10]) as usize;
+        let frames = read_frames(&output[10..10 + size], 3);
+        let ids: Vec<_> = frames.iter().map(|frame| &frame.id).collect();
+        assert_eq!(ids, [b"COMM", b"TIT2", b"TPE1", b"TRCK", b"TYER"]);
+
+        let title: Vec<u16> = frames[1].data[3..]
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&title).unwrap(), "Título");
+        assert_eq!(
+            &frames[4].data[3..],
+            "2019"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(&output[10 + size..], [0xFF, 0xFB, 0x90, 0x00]);
+    }
+
+    #[test]
+    fn test_vorbis_comment() {
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&4u32.to_le_bytes());
+        tags.extend_from_slice(b"test");
+        tags.extend_from_slice(&2u32.to_le_bytes());
+        for comment in [&b"TITLE=old"[..], b"ENCODER=x"] {
+            tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            tags.extend_from_slice(comment);
+        }
+
+        let mut file = Vec::new();
+        let headers = [b"OpusHead\x01".to_vec(), tags];
+        for page in paginate(&headers[..1], 7, 0, true) {
+            page.write(&mut file).unwrap();
+        }
+        for page in paginate(&headers[1..], 7, 1, false) {
+            page.write(&mut file).unwrap();
+        }
+        let audio = Page {
+            header_type: 0x04,
+            granule: 960,
+            serial: 7,
+            sequence: 2,
+            lacing: vec![3],
+            data: vec![1, 2, 3],
+        };
+        audio.write(&mut file).unwrap();
+
+        let mut output = Vec::new();
+        write_vorbis(&mut Cursor::new(file), &mut output, &metadata()).unwrap();
+
+        let mut reader = Cursor::new(output);
+        let pages: Vec<Page> = std::iter::from_fn(|| Page::read(&mut reader).unwrap()).collect();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[2].data, [1, 2, 3]);
+        assert_eq!(pages[2].sequence, 2);
+
+        let comments = String::from_utf8_lossy(&pages[1].data);
+        assert!(comments.contains("ENCODER=x"));
+        assert!(comments.contains("TITLE=Título"));
+        assert!(comments.contains("TRACKNUMBER=3/12"));
+        assert!(!comments.contains("TITLE=old"));
+    }
+
+    #[test]
+    fn test_ogg_crc() {
+        // the checksum of a page with its own checksum in place is zero
+        let page = Page {
+            header_type: 0x02,
+            granule: 0,
+            serial: 1,
+            sequence: 0,
+            lacing: vec![1],
+            data: vec![0],
+        };
+        let mut bytes = Vec::new();
+        page.write(&mut bytes).unwrap();
+        let crc = u32::from_le_bytes(bytes[22..26].try_into().unwrap());
+        bytes[22..26].fill(0);
+        assert_eq!(ogg_crc(&bytes), crc);
+    }
+
+    #[test]
+    fn test_ilst_moves_chunk_offsets() {
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", |out| {
+            out.extend_from_slice(b"M4A \0\0\0\0")
+        });
+        let moov_start = file.len() as u32;
+
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"trak", |out| {
+            write_box(out, b"mdia", |out| {
+                write_box(out, b"minf", |out| {
+                    write_box(out, b"stbl", |out| {
+                        write_full_box(out, b"stco", 0, 0, |out| {
+                            put_u32(out, 1);
+                            put_u32(out, 0);
+                        });
+                    });
+                });
+            });
+        });
+        let mdat = moov_start + 8 + moov.len() as u32;
+        let offset_pos = moov.len() - 4;
+        moov[offset_pos..].copy_from_slice(&(mdat + 8).to_be_bytes());
+        write_box(&mut file, b"moov", |out| out.extend_from_slice(&moov));
+        write_box(&mut file, b"mdat", |out| out.extend_from_slice(b"data"));
+
+        let length = file.len() as u64;
+        let mut output = Vec::new();
+        write_ilst(&mut Cursor::new(file), &mut output, length, &metadata()).unwrap();
+
+        let boxes = bmff::children(&output).unwrap();
+        let new_moov = boxes[1].1;
+        let stco = bmff::require(new_moov, &[b"trak", b"mdia", b"minf", b"stbl", b"stco"]).unwrap();
+        let offset = u32::from_be_bytes(stco[8..12].try_into().unwrap()) as usize;
+        assert_eq!(&output[offset..offset + 4], b"data");
+
+        let ilst = bmff::require(new_moov, &[b"udta", b"meta"]).unwrap();
+        let ilst = bmff::require(&ilst[4..], &[b"ilst"]).unwrap();
+        let title = bmff::require(ilst, &[b"\xA9nam", b"data"]).unwrap();
+        assert_eq!(&title[8..], "Título".as_bytes());
+        let track = bmff::require(ilst, &[b"trkn", b"data"]).unwrap();
+        assert_eq!(&track[8..], [0, 0, 0, 3, 0, 12, 0, 0]);
+    }
+
+    #[test]
+    fn test_ilst_malformed_moov() {
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", |out| {
+            out.extend_from_slice(b"M4A \0\0\0\0")
+        });
+        write_box(&mut file, b"moov", |out| {
+            write_box(out, b"trak", |out| out.extend_from_slice(b"data"));
+            // a child box claiming more bytes than moov holds
+            put_u32(out, 64);
+            out.extend_from_slice(b"udta");
+        });
+        write_box(&mut file, b"mdat", |out| out.extend_from_slice(b"data"));
+
+        let path = std::env::temp_dir().join(format!("ccobalt-{:x}.m4a", fastrand::u64(..)));
+        std::fs::write(&path, &file).unwrap();
+
+        let err = write_tags(&path, &metadata()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), file);
+
+        let mut temp_name = path.file_name().unwrap().to_os_string();
+        temp_name.push(".tags");
+        assert!(!path.with_file_name(temp_name).exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
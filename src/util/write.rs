@@ -1,23 +1,759 @@
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use reqwest::{Client, StatusCode};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+use crate::util::filetype;
+use crate::util::progress::ProgressSender;
+use crate::util::stream::{self, ResponseInfo, SNIFF_LEN, StreamError};
 
 /// Writes the byte stream to a file after detecting the file type.
 /// Returns the path to the written file.
-pub fn save_to_file(
+///
+/// The base name is sanitized and an existing file with the same name is overwritten,
+/// see [`safe_path`].
+pub async fn save_to_file(
     bytes: &[u8],
     base_name: &str,
-    directory: &str,
+    directory: impl AsRef<Path>,
 ) -> Result<PathBuf, std::io::Error> {
     let file_type = crate::util::filetype::get_sig(bytes);
     let extension = file_type.map(|t| t.as_str()).unwrap_or("bin"); // fallback if type is unknown
 
-    let mut path = PathBuf::from(directory);
-    path.push(format!("{}.{}", base_name, extension));
+    write_file(
+        bytes,
+        &format!("{base_name}.{extension}"),
+        directory.as_ref(),
+    )
+    .await
+}
+
+/// Same as [`save_to_file`], using the response metadata from
+/// [`read_stream_with_info`](stream::read_stream_with_info) when the file type is not
+/// detected from the bytes.
+///
+/// The extension is picked with [`ResponseInfo::extension`].
+pub async fn save_to_file_with(
+    bytes: &[u8],
+    base_name: &str,
+    directory: impl AsRef<Path>,
+    info: &ResponseInfo,
+) -> Result<PathBuf, std::io::Error> {
+    let extension = info.extension(None);
 
-    let mut file = File::create(&path)?;
-    file.write_all(bytes)?;
-    file.flush()?;
+    write_file(
+        bytes,
+        &format!("{base_name}.{extension}"),
+        directory.as_ref(),
+    )
+    .await
+}
+
+async fn write_file(bytes: &[u8], name: &str, directory: &Path) -> std::io::Result<PathBuf> {
+    let path = safe_path(directory, name, Collision::Overwrite)?.into_path();
+
+    let temp = TempPath::new(&path);
+    let mut file = tokio::fs::File::create(temp.path()).await?;
+    file.write_all(bytes).await?;
+    file.sync_all().await?;
+    drop(file);
+    temp.persist(&path).await?;
 
     Ok(path)
 }
+
+/// A temporary file next to its final path, removed on drop unless it is persisted or kept.
+///
+/// Files are written under a temporary name and renamed into place once complete, so a
+/// crash or a cancelled download never leaves a truncated file under the final name.
+#[derive(Debug)]
+pub(crate) struct TempPath {
+    path: PathBuf,
+    armed: bool,
+}
+
+impl TempPath {
+    /// Creates a hidden, unique temporary path in the directory of `target`, keeping its
+    /// extension so tools that look at it still recognize the format.
+    pub(crate) fn new(target: &Path) -> Self {
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let path = target.with_file_name(format!(".{:08x}.{name}", fastrand::u32(..)));
+
+        Self::at(path)
+    }
+
+    /// Wraps an existing path, such as a `.part` file.
+    pub(crate) fn at(path: PathBuf) -> Self {
+        Self { path, armed: true }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Leaves the file in place when this is dropped.
+    pub(crate) fn keep(mut self) {
+        self.armed = false;
+    }
+
+    /// Renames the file to `target`.
+    ///
+    /// The writer must have synced the file before, this only flushes the directory entry.
+    pub(crate) async fn persist(mut self, target: &Path) -> std::io::Result<()> {
+        tokio::fs::rename(&self.path, target).await?;
+        self.armed = false;
+
+        sync_parent(target).await;
+        Ok(())
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.armed {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Flushes the directory entry of a renamed file, where the platform supports it.
+async fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Ok(dir) = tokio::fs::File::open(parent).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// How the file saved by [`save_stream_as`] is named.
+#[derive(Debug, Clone, Copy)]
+pub enum SaveName<'a> {
+    /// The given base name, with the extension of the file name suggested by cobalt if
+    /// any, or else one detected from the file, see [`ResponseInfo::extension`].
+    Base(&'a str, Option<&'a str>),
+    /// The file name suggested by cobalt, falling back to the `Content-Disposition` header
+    /// of the response and then to `download`.
+    ///
+    /// The name is sanitized, and an extension is detected from the first bytes of the
+    /// file if it has none.
+    Suggested(Option<&'a str>),
+}
+
+/// What to do when the file being saved already exists.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Collision {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Keep the existing file and don't save the new one.
+    Skip,
+    /// Save under a free name, adding ` (1)`, ` (2)` and so on before the extension.
+    Suffix,
+}
+
+/// A path returned by [`safe_path`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SafePath {
+    /// The file should be written to this path.
+    Write(PathBuf),
+    /// The file exists and [`Collision::Skip`] was requested.
+    Exists(PathBuf),
+}
+
+impl SafePath {
+    /// Returns the path, whether it is to be written or already exists.
+    pub fn into_path(self) -> PathBuf {
+        match self {
+            SafePath::Write(path) | SafePath::Exists(path) => path,
+        }
+    }
+}
+
+/// Characters that are not allowed in file names on common filesystems.
+const RESERVED: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Device names that Windows does not allow as file names, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Maximum length of a file name in bytes.
+///
+/// Most filesystems allow 255 bytes, the rest is left for the `.part` and ` (n)` suffixes.
+pub const MAX_NAME_LEN: usize = 240;
+
+/// Longest extension that is kept when a name is shortened.
+const MAX_EXTENSION_LEN: usize = 16;
+
+/// Makes a file name safe to use on common filesystems.
+///
+/// Path separators, reserved and control characters are replaced with `_`, leading and
+/// trailing dots and spaces are removed, Windows device names such as `CON` get a leading
+/// `_`, and the name is shortened to [`MAX_NAME_LEN`] bytes, keeping the extension.
+/// Returns `None` if nothing usable is left.
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || RESERVED.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let name = name.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if name.is_empty() {
+        return None;
+    }
+
+    let (stem, _) = split_extension(name);
+    let name = if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.trim_end().eq_ignore_ascii_case(reserved))
+    {
+        format!("_{name}")
+    } else {
+        name.to_string()
+    };
+
+    Some(truncate_name(&name, "", MAX_NAME_LEN))
+}
+
+/// Splits a file name into its stem and extension, if it has a reasonable one.
+fn split_extension(name: &str) -> (&str, Option<&str>) {
+    match name.rsplit_once('.') {
+        Some((stem, extension))
+            if !stem.is_empty()
+                && !extension.is_empty()
+                && extension.len() <= MAX_EXTENSION_LEN
+                && extension.chars().all(char::is_alphanumeric) =>
+        {
+            (stem, Some(extension))
+        }
+        _ => (name, None),
+    }
+}
+
+/// Adds `suffix` before the extension, shortening the stem so the result fits in `max_len`
+/// bytes.
+fn truncate_name(name: &str, suffix: &str, max_len: usize) -> String {
+    let (stem, extension) = split_extension(name);
+    let extension = extension
+        .map(|extension| format!(".{extension}"))
+        .unwrap_or_default();
+
+    let mut stem_len = max_len
+        .saturating_sub(extension.len() + suffix.len())
+        .min(stem.len());
+    while !stem.is_char_boundary(stem_len) {
+        stem_len -= 1;
+    }
+
+    format!("{}{suffix}{extension}", stem[..stem_len].trim_end())
+}
+
+/// Builds a path for saving `name` into `directory`.
+///
+/// The name is sanitized with [`sanitize_filename`], `download` is used if nothing is
+/// left of it. The returned path is always a direct child of `directory`. When a file
+/// with the same name exists, `collision` decides what happens.
+pub fn safe_path(directory: &Path, name: &str, collision: Collision) -> std::io::Result<SafePath> {
+    let name = sanitize_filename(name).unwrap_or_else(|| "download".to_string());
+
+    // sanitizing leaves no separators, but make sure the name cannot leave the directory
+    let mut components = Path::new(&name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid file name: {name}"),
+        ));
+    }
+
+    let path = directory.join(&name);
+
+    match collision {
+        Collision::Overwrite => Ok(SafePath::Write(path)),
+        Collision::Skip if path.try_exists()? => Ok(SafePath::Exists(path)),
+        Collision::Skip => Ok(SafePath::Write(path)),
+        Collision::Suffix => {
+            let mut path = path;
+            let mut counter = 1;
+            while path.try_exists()? {
+                path = directory.join(truncate_name(&name, &format!(" ({counter})"), MAX_NAME_LEN));
+                counter += 1;
+            }
+            Ok(SafePath::Write(path))
+        }
+    }
+}
+
+/// Streams the body from the given URL into a file without buffering it in memory.
+///
+/// Same as [`save_stream_as`] with [`SaveName::Base`].
+pub async fn save_stream(
+    client: Arc<Client>,
+    url: Url,
+    base_name: &str,
+    directory: impl AsRef<Path>,
+    progress: Option<&ProgressSender>,
+) -> Result<PathBuf, StreamError> {
+    save_stream_as(
+        client,
+        url,
+        SaveName::Base(base_name, None),
+        directory,
+        Collision::Overwrite,
+        progress,
+        None,
+    )
+    .await
+}
+
+/// Streams the body from the given URL into a file without buffering it in memory.
+///
+/// The data is written to a `.part` file first, flushed to disk and renamed once the name
+/// is known, so the final name never holds a partial file.
+/// If a `.part` file is left over from an interrupted download, the download is resumed
+/// with a `Range` request. The `ETag` or `Last-Modified` validator and the size of the
+/// file are recorded in a `.part.meta` file and checked against the partial response,
+/// so a part from another file or an older version is discarded instead of appended to;
+/// servers that ignore the range cause a full restart.
+/// When a download fails, the `.part` file is kept only if the server accepts ranges;
+/// a download that is dropped midway removes it.
+/// Progress updates are published to `progress`, if set. Files over `max_bytes` fail with
+/// [`StreamError::TooLarge`] and their `.part` file is removed, see [`stream::copy_body`].
+///
+/// The final name is built with [`safe_path`]. With [`Collision::Skip`], nothing is
+/// downloaded if the name is known upfront and the file exists.
+///
+/// Returns the path to the written file, or to the existing one if it was skipped.
+pub async fn save_stream_as(
+    client: Arc<Client>,
+    url: Url,
+    name: SaveName<'_>,
+    directory: impl AsRef<Path>,
+    collision: Collision,
+    progress: Option<&ProgressSender>,
+    max_bytes: Option<u64>,
+) -> Result<PathBuf, StreamError> {
+    let directory = directory.as_ref();
+    let suggested = match name {
+        SaveName::Base(..) => None,
+        SaveName::Suggested(filename) => filename.and_then(sanitize_filename),
+    };
+
+    if collision == Collision::Skip
+        && let Some(filename) = &suggested
+        && split_extension(filename).1.is_some()
+        && let SafePath::Exists(path) = safe_path(directory, filename, collision)?
+    {
+        return Ok(path);
+    }
+
+    let part_name = match (name, &suggested) {
+        (SaveName::Base(base_name, _), _) => format!(
+            "{}.part",
+            sanitize_filename(base_name).unwrap_or_else(|| "download".to_string())
+        ),
+        (_, Some(filename)) => format!("{filename}.part"),
+        // the name is only known from the response, so key the part file by URL
+        (_, None) => format!(".{:016x}.part", url_hash(&url)),
+    };
+
+    let part_path = directory.join(&part_name);
+    let meta_path = directory.join(format!("{part_name}.meta"));
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&part_path)
+        .await?;
+    let mut offset = file.metadata().await?.len();
+
+    // removes the part file on errors and when the download is dropped midway
+    let part = TempPath::at(part_path);
+    let meta = TempPath::at(meta_path);
+
+    // without a record of what it holds, the part file cannot be checked against the
+    // server and may belong to another file with the same name
+    let resume = match offset {
+        0 => None,
+        _ => PartMeta::read(meta.path()).await,
+    };
+    if resume.is_none() && offset > 0 {
+        file.set_len(0).await?;
+        offset = 0;
+    }
+
+    let response = loop {
+        let validator = resume
+            .as_ref()
+            .and_then(|record| record.validator.as_deref());
+        let response =
+            match stream::open_range(Arc::clone(&client), url.clone(), offset, validator).await {
+                Ok(response) => response,
+                Err(err) => {
+                    if offset > 0 {
+                        part.keep();
+                        meta.keep();
+                    }
+                    return Err(err.into());
+                }
+            };
+
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT
+                if offset > 0
+                    && resume.as_ref().is_some_and(|record| {
+                        record.matches(stream::content_range(&response), offset)
+                    }) =>
+            {
+                break response;
+            }
+            // the part file already holds everything, is stale or belongs to another file
+            StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+                file.set_len(0).await?;
+                offset = 0;
+            }
+            _ => {
+                let response = match response.error_for_status() {
+                    Ok(response) => response,
+                    Err(err) => {
+                        // a failing server is no reason to throw away what was downloaded
+                        if offset > 0 {
+                            part.keep();
+                            meta.keep();
+                        }
+                        return Err(err.into());
+                    }
+                };
+
+                // the server ignored the range and sent the full body
+                file.set_len(0).await?;
+                offset = 0;
+                break response;
+            }
+        }
+    };
+
+    // a fresh download can only be resumed later if the server accepts ranges
+    let resumable = offset > 0
+        || match PartMeta::from_response(&response) {
+            Some(record) if stream::accepts_ranges(&response) => {
+                record.write(meta.path()).await.is_ok()
+            }
+            _ => false,
+        };
+    let mut info = ResponseInfo::from_response(&response);
+
+    if let Err(err) = stream::copy_body(response, &mut file, offset, progress, max_bytes).await {
+        drop(file);
+        if resumable && !matches!(err, StreamError::TooLarge { .. }) {
+            part.keep();
+            meta.keep();
+        }
+        return Err(err);
+    }
+    file.sync_all().await?;
+    drop(file);
+
+    info.file_type = filetype::get_sig(&read_head(part.path()).await?);
+
+    let filename = match name {
+        SaveName::Base(base_name, filename) => {
+            format!("{base_name}.{}", info.extension(filename))
+        }
+        SaveName::Suggested(_) => {
+            let filename = suggested
+                .or_else(|| info.filename.as_deref().and_then(sanitize_filename))
+                .unwrap_or_else(|| "download".to_string());
+
+            if Path::new(&filename).extension().is_some() {
+                filename
+            } else {
+                format!("{filename}.{}", info.extension(None))
+            }
+        }
+    };
+
+    let path = match safe_path(directory, &filename, collision)? {
+        SafePath::Write(path) => path,
+        SafePath::Exists(path) => return Ok(path),
+    };
+
+    part.persist(&path).await?;
+
+    Ok(path)
+}
+
+/// What a `.part` file holds, recorded next to it as `<name>.part.meta` so that a resumed
+/// download is only appended to the same version of the same file.
+#[derive(Debug, PartialEq)]
+struct PartMeta {
+    /// `ETag` or `Last-Modified` value, sent back in `If-Range`.
+    validator: Option<String>,
+    /// Size of the whole file.
+    total: Option<u64>,
+}
+
+impl PartMeta {
+    /// Returns `None` if the response carries nothing to check a later range against.
+    fn from_response(response: &reqwest::Response) -> Option<Self> {
+        let meta = Self {
+            validator: stream::range_validator(response),
+            total: response.content_length(),
+        };
+
+        (meta.validator.is_some() || meta.total.is_some()).then_some(meta)
+    }
+
+    async fn read(path: &Path) -> Option<Self> {
+        let contents = tokio::fs::read_to_string(path).await.ok()?;
+        let (validator, total) = contents.split_once('\n')?;
+
+        let meta = Self {
+            validator: (!validator.is_empty()).then(|| validator.to_string()),
+            total: total.trim().parse().ok(),
+        };
+
+        (meta.validator.is_some() || meta.total.is_some()).then_some(meta)
+    }
+
+    async fn write(&self, path: &Path) -> std::io::Result<()> {
+        let total = self
+            .total
+            .map(|total| total.to_string())
+            .unwrap_or_default();
+        let contents = format!(
+            "{}\n{total}\n",
+            self.validator.as_deref().unwrap_or_default()
+        );
+
+        tokio::fs::write(path, contents).await
+    }
+
+    /// Returns whether a partial response with the given `Content-Range` continues the part
+    /// file of `offset` bytes.
+    fn matches(&self, content_range: Option<(u64, Option<u64>)>, offset: u64) -> bool {
+        let Some((start, total)) = content_range else {
+            return false;
+        };
+
+        start == offset
+            && match (self.total, total) {
+                (Some(expected), Some(total)) => expected == total,
+                _ => true,
+            }
+    }
+}
+
+/// Hashes the URL with 64-bit FNV-1a, which, unlike `DefaultHasher`, stays the same across
+/// Rust releases.
+///
+/// The expiry and signature parameters of cobalt tunnels are left out, other query
+/// parameters are kept since they often select the file, e.g. on redirects to a CDN.
+fn url_hash(url: &Url) -> u64 {
+    let mut url = url.clone();
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !matches!(key.as_ref(), "exp" | "sig" | "sec" | "iv"))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.set_fragment(None);
+    url.set_query(None);
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+
+    url.as_str()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Reads the first bytes of a file for file type detection.
+async fn read_head(path: &Path) -> Result<Vec<u8>, std::io::Error> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut head).await?;
+
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_hash() {
+        let url = |url: &str| Url::parse(url).unwrap();
+
+        assert_eq!(url_hash(&url("http://a")), 0x80f6_2ee3_1b62_9023);
+        assert_eq!(
+            url_hash(&url(
+                "https://a.example/tunnel?id=1&exp=2&sig=abc&sec=s&iv=i"
+            )),
+            url_hash(&url(
+                "https://a.example/tunnel?id=1&exp=3&sig=def&sec=t&iv=j#x"
+            ))
+        );
+        assert_ne!(
+            url_hash(&url("https://a.example/tunnel?id=1")),
+            url_hash(&url("https://a.example/tunnel?id=2"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_part_meta() {
+        let path = std::env::temp_dir().join(format!("ccobalt-{}.part.meta", std::process::id()));
+        let meta = PartMeta {
+            validator: Some("\"abc\"".to_string()),
+            total: Some(1000),
+        };
+        meta.write(&path).await.unwrap();
+        assert_eq!(PartMeta::read(&path).await, Some(meta));
+
+        let meta = PartMeta::read(&path).await.unwrap();
+        assert!(meta.matches(Some((100, Some(1000))), 100));
+        assert!(meta.matches(Some((100, None)), 100));
+        assert!(!meta.matches(Some((0, Some(1000))), 100));
+        assert!(!meta.matches(Some((100, Some(2000))), 100));
+        assert!(!meta.matches(None, 100));
+
+        std::fs::write(&path, "\n\n").unwrap();
+        assert_eq!(PartMeta::read(&path).await, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_keeps_part_on_error_status() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/file", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await;
+            let _ = socket
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await;
+        });
+
+        let directory = std::env::temp_dir().join(format!("ccobalt-{}-resume", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let part_path = directory.join("video.part");
+        let meta_path = directory.join("video.part.meta");
+        std::fs::write(&part_path, b"abc").unwrap();
+        std::fs::write(&meta_path, "\"abc\"\n6\n").unwrap();
+
+        let client = Arc::new(Client::builder().no_proxy().build().unwrap());
+        let result = save_stream(client, url, "video", directory.to_str().unwrap(), None).await;
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&part_path).unwrap(), b"abc");
+        assert!(meta_path.exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(
+            sanitize_filename("youtube_dQw4w9WgXcQ_1920x1080_h264.mp4").as_deref(),
+            Some("youtube_dQw4w9WgXcQ_1920x1080_h264.mp4")
+        );
+        assert_eq!(
+            sanitize_filename("AC/DC: Back in Black?.mp3").as_deref(),
+            Some("AC_DC_ Back in Black_.mp3")
+        );
+        assert_eq!(
+            sanitize_filename("../../etc/passwd").as_deref(),
+            Some("_.._etc_passwd")
+        );
+        assert_eq!(sanitize_filename(" .. "), None);
+        assert_eq!(sanitize_filename("con.mp3").as_deref(), Some("_con.mp3"));
+        assert_eq!(sanitize_filename("a\0b").as_deref(), Some("a_b"));
+    }
+
+    #[test]
+    fn test_truncate_name() {
+        let long = format!("{}.mp4", "я".repeat(200));
+        let name = sanitize_filename(&long).unwrap();
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(name.ends_with("я.mp4"));
+
+        assert_eq!(truncate_name("video.mp4", " (2)", 64), "video (2).mp4");
+        assert_eq!(
+            truncate_name("no extension", " (1)", 64),
+            "no extension (1)"
+        );
+    }
+
+    #[test]
+    fn test_safe_path_collision() {
+        let directory = std::env::temp_dir().join(format!("ccobalt-{:x}", fastrand::u64(..)));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("a.mp4"), b"").unwrap();
+        std::fs::write(directory.join("a (1).mp4"), b"").unwrap();
+
+        assert_eq!(
+            safe_path(&directory, "a.mp4", Collision::Overwrite).unwrap(),
+            SafePath::Write(directory.join("a.mp4"))
+        );
+        assert_eq!(
+            safe_path(&directory, "a.mp4", Collision::Skip).unwrap(),
+            SafePath::Exists(directory.join("a.mp4"))
+        );
+        assert_eq!(
+            safe_path(&directory, "a.mp4", Collision::Suffix).unwrap(),
+            SafePath::Write(directory.join("a (2).mp4"))
+        );
+        assert_eq!(
+            safe_path(&directory, "../a.mp4", Collision::Overwrite).unwrap(),
+            SafePath::Write(directory.join("_a.mp4"))
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_temp_path() {
+        let directory = std::env::temp_dir().join(format!("ccobalt-{:x}", fastrand::u64(..)));
+        std::fs::create_dir_all(&directory).unwrap();
+        let target = directory.join("video.mp4");
+
+        let temp = TempPath::new(&target);
+        let temp_path = temp.path().to_path_buf();
+        assert_eq!(temp_path.extension().unwrap(), "mp4");
+        std::fs::write(&temp_path, b"partial").unwrap();
+        drop(temp);
+        assert!(!temp_path.exists());
+
+        let path = save_to_file(b"\x89PNG\r\n\x1a\n", "image", &directory)
+            .await
+            .unwrap();
+        assert_eq!(path, directory.join("image.png"));
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}